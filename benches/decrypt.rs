@@ -0,0 +1,156 @@
+//! Throughput of reading entry data, decryption and decompression included, for each
+//! [`DecryptMode`]
+//!
+//! Run with `cargo bench`. The archive is built in memory, with the first bytes of every file
+//! encrypted the way a game of each scheme would.
+
+use std::{hint::black_box, io::Cursor};
+
+use cat_nipa::{
+    DecryptMode, GameKeys, NpaEntry, NpaHead, NpaWriter, TextMode, decrypt_data, parse_head,
+    read_entries, read_entry_data_generic,
+};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use strum::IntoEnumIterator;
+
+const FILE_COUNT: usize = 64;
+const FILE_SIZE: usize = 0x4000;
+
+/// Offset of the header's `encrypted` flag, after the magic, both keys and the `compressed` flag
+const ENCRYPTED_OFFSET: usize = 7 + 4 + 4 + 1;
+
+fn file_data(index: usize) -> Vec<u8> {
+    (0..FILE_SIZE)
+        .map(|x| (x / 64 + x * index % 7) as u8)
+        .collect()
+}
+
+struct Archive {
+    bytes: Vec<u8>,
+    header: NpaHead,
+    entries: Vec<NpaEntry>,
+}
+
+/// Packs `FILE_COUNT` files and encrypts their data for `game`
+///
+/// The writer only produces unencrypted archives, so the encrypted region of each file is
+/// replaced with the inverse of the decryption afterwards.
+fn build_archive(game: &impl GameKeys, compressed: bool) -> Archive {
+    let mut writer = NpaWriter::new().compressed(compressed);
+
+    for index in 0..FILE_COUNT {
+        writer.add_file(format!("bench/{index:03}.dat"), file_data(index));
+    }
+
+    let mut bytes = Vec::new();
+    writer.write(&mut bytes).unwrap();
+    bytes[ENCRYPTED_OFFSET] = 1;
+
+    let mut reader = Cursor::new(&bytes);
+    let header = parse_head(&mut reader).unwrap();
+    let entries = read_entries(&mut reader, &header, false).unwrap();
+
+    let mut inverse = [0u8; 256];
+    for (index, value) in game.encryption_key().iter().enumerate() {
+        inverse[*value as usize] = index as u8;
+    }
+
+    let mode = game.decrypt_mode();
+
+    for entry in entries.iter().filter(|entry| !entry.is_directory()) {
+        let key = decrypt_data(entry, &header, game);
+        let start = entry.data_offset(&header) as usize;
+
+        let mut len = 0x1000;
+        if mode != DecryptMode::Lamento {
+            len += entry.un_decoded_file_path.len();
+        }
+        let len = len.min(entry.compressed_size as usize);
+
+        for (x, byte) in bytes[start..start + len].iter_mut().enumerate() {
+            *byte = match mode {
+                DecryptMode::Lamento => inverse[byte.wrapping_add(key) as usize],
+                DecryptMode::Totono => {
+                    let r = !byte.wrapping_add(key).wrapping_add(x as u8);
+                    inverse[inverse[inverse[r as usize] as usize] as usize]
+                }
+                DecryptMode::Standard => {
+                    inverse[byte.wrapping_add(key).wrapping_add(x as u8) as usize]
+                }
+            };
+        }
+    }
+
+    Archive {
+        bytes,
+        header,
+        entries,
+    }
+}
+
+fn read_all(archive: &Archive, game: &impl GameKeys) -> usize {
+    let mut reader = Cursor::new(&archive.bytes);
+
+    archive
+        .entries
+        .iter()
+        .filter(|entry| !entry.is_directory())
+        .map(|entry| {
+            read_entry_data_generic(&mut reader, &archive.header, entry, game, TextMode::Raw)
+                .unwrap()
+                .len()
+        })
+        .sum()
+}
+
+fn bench_read_entry_data(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_entry_data");
+    group.throughput(Throughput::Bytes((FILE_COUNT * FILE_SIZE) as u64));
+
+    for mode in DecryptMode::iter() {
+        let game = mode.games()[0];
+
+        for compressed in [false, true] {
+            let archive = build_archive(&game, compressed);
+
+            // Make sure the bytes are encrypted correctly, so the decrypted data is what was packed
+            let mut reader = Cursor::new(&archive.bytes);
+            let last = archive.entries.last().unwrap();
+            let data =
+                read_entry_data_generic(&mut reader, &archive.header, last, &game, TextMode::Raw)
+                    .unwrap();
+            assert_eq!(data, file_data(FILE_COUNT - 1));
+
+            let name = if compressed { "compressed" } else { "stored" };
+            group.bench_with_input(
+                BenchmarkId::new(format!("{mode:?}"), name),
+                &archive,
+                |b, archive| b.iter(|| read_all(archive, &game)),
+            );
+        }
+    }
+
+    group.finish();
+}
+
+/// Decryption alone, over one encrypted region of the largest size without a file name
+fn bench_transform_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("transform_buffer");
+    group.throughput(Throughput::Bytes(0x1000));
+
+    let mut buffer = file_data(1)[..0x1000].to_vec();
+
+    for mode in DecryptMode::iter() {
+        let game = mode.games()[0];
+        let decryptor = game.decryptor();
+
+        group.bench_function(format!("{mode:?}"), |b| {
+            b.iter(|| decryptor.transform_buffer(black_box(&mut buffer), black_box(0x5A)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_read_entry_data, bench_transform_buffer);
+criterion_main!(benches);
@@ -1,5 +1,40 @@
+//! Key derivation for NPA archives
+//!
+//! Both file names and data are encrypted with single-byte keys that are added to, or subtracted
+//! from, each stored byte. The functions here derive those keys. Names are decrypted by the
+//! readers in the crate root, data by the [`Decryptor`] of each game.
+
 use super::*;
 
+/// Returns the key that is added to byte `current_number` of the name of entry `current_file`
+///
+/// The key starts out as `0xFC * current_number`. A value derived from the header keys is then
+/// subtracted from it: their sum for games that have [`GameKeys::add_bytes_if_encrypted`] set and
+/// are reading an encrypted archive, their product otherwise. Subtracting a `u32` here means
+/// subtracting each of its four bytes, and the same is done with the entry's index. The lowest
+/// byte of the result is the key.
+///
+/// ```
+/// use cat_nipa::{NpaHead, decrypt_header};
+///
+/// let header = NpaHead {
+///     head: *b"NPA\x01\0\0\0",
+///     key_1: 0x1234,
+///     key_2: 0x5678,
+///     encrypted: true,
+///     compressed: false,
+///     file_count: 1,
+///     folder_count: 0,
+///     total_count: 1,
+///     start: 0,
+/// };
+///
+/// // 0x1234 * 0x5678 = 0x06260060, whose bytes sum up to 0x8C
+/// assert_eq!(decrypt_header(0, 0, &header, false), 0u8.wrapping_sub(0x8C));
+///
+/// // Every following byte of the name moves the key up by 0xFC
+/// assert_eq!(decrypt_header(1, 0, &header, false), 0xFCu8.wrapping_sub(0x8C));
+/// ```
 pub fn decrypt_header(
     current_number: u32,
     current_file: u32,
@@ -29,19 +64,18 @@ pub fn decrypt_header(
     (key & 0xFF) as u8
 }
 
-pub fn decrypt_data(entry: &NpaEntry, header: &NpaHead, game: Game) -> u8 {
-    let mut key_1: u32 = match game {
-        Game::Axanael
-        | Game::Kikokugai
-        | Game::SonicomiTrialTwo
-        | Game::Sonicomi
-        | Game::LostX
-        | Game::DramaticalMurder
-        | Game::DramaticalMurderReConnect
-        | Game::MuramasaSS => 0x20101118,
-        Game::Totono => 0x12345678,
-        _ => 0x87654321,
-    };
+/// Returns the entry key used to decrypt the data of `entry`
+///
+/// The key starts out as [`GameKeys::data_key_seed`], every byte of the still encrypted name is
+/// subtracted from it and the result is multiplied by the name length. Except for
+/// [`DecryptMode::Lamento`], the product of the header keys is then added and the sum multiplied
+/// by the entry's original size. The lowest byte of the result is the key.
+///
+/// Each of the first 0x1000 bytes of the data (plus the name length, except for Lamento) is then
+/// substituted through [`GameKeys::encryption_key`], and the key and, except for Lamento, the
+/// byte's position are subtracted from it.
+pub fn decrypt_data<G: GameKeys + ?Sized>(entry: &NpaEntry, header: &NpaHead, game: &G) -> u8 {
+    let mut key_1 = game.data_key_seed();
 
     for byte in entry.un_decoded_file_path.iter() {
         key_1 = key_1.wrapping_sub(*byte as u32);
@@ -51,10 +85,144 @@ pub fn decrypt_data(entry: &NpaEntry, header: &NpaHead, game: Game) -> u8 {
 
     let mut key = key_1.wrapping_mul(entry.name_length);
 
-    if game != Game::Lamento && game != Game::LamentoTrail {
+    if game.decrypt_mode() != DecryptMode::Lamento {
         key = key.wrapping_add(key_2);
         key = key.wrapping_mul(entry.original_size);
     }
 
     key as u8
 }
+
+/// Turns the encrypted region at the start of an entry's data back into plain bytes
+///
+/// [`GameKeys::decryptor`] picks one for each game. Reading a game whose scheme isn't one of
+/// [`DecryptMode`] takes implementing [`Decryptor::transform`] and returning it from there, or
+/// wrapping a game in [`WithDecryptor`](crate::WithDecryptor).
+pub trait Decryptor {
+    /// Decrypts `byte` at position `index` of the entry's data, `key` is the entry key from
+    /// [`decrypt_data`]
+    fn transform(&self, byte: u8, index: u32, key: u8) -> u8;
+
+    /// Decrypts the whole encrypted region, calling [`Decryptor::transform`] for each byte unless
+    /// an implementation has a faster way
+    fn transform_buffer(&self, buffer: &mut [u8], key: u8) {
+        for (index, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.transform(*byte, index as u32, key);
+        }
+    }
+}
+
+impl<D: Decryptor + ?Sized> Decryptor for &D {
+    fn transform(&self, byte: u8, index: u32, key: u8) -> u8 {
+        (**self).transform(byte, index, key)
+    }
+
+    fn transform_buffer(&self, buffer: &mut [u8], key: u8) {
+        (**self).transform_buffer(buffer, key)
+    }
+}
+
+/// The built-in [`Decryptor`] of each [`DecryptMode`], substituting bytes through a game's key
+/// table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemeDecryptor {
+    pub mode: DecryptMode,
+
+    /// Substitution table applied to each encrypted byte, see [`GameKeys::encryption_key`]
+    pub table: [u8; 256],
+}
+
+impl Decryptor for SchemeDecryptor {
+    fn transform(&self, byte: u8, index: u32, key: u8) -> u8 {
+        let table = &self.table;
+
+        match self.mode {
+            DecryptMode::Lamento => table[byte as usize].wrapping_sub(key),
+
+            DecryptMode::Totono => {
+                let mut r = byte;
+                r = table[r as usize];
+                r = table[r as usize];
+                r = table[r as usize];
+                r = !r;
+
+                r.wrapping_sub(key).wrapping_sub(index as u8)
+            }
+
+            DecryptMode::Standard => table[byte as usize]
+                .wrapping_sub(key)
+                .wrapping_sub(index as u8),
+        }
+    }
+
+    fn transform_buffer(&self, buffer: &mut [u8], key: u8) {
+        #[cfg(feature = "simd")]
+        let start = match self.mode {
+            DecryptMode::Standard => decrypt_standard_simd(buffer, &self.table, key),
+            _ => 0,
+        };
+
+        #[cfg(not(feature = "simd"))]
+        let start = 0;
+
+        // The mode is matched once instead of for every byte, so each loop stays branch free
+        match self.mode {
+            DecryptMode::Lamento => {
+                for byte in &mut buffer[start..] {
+                    *byte = self.table[*byte as usize].wrapping_sub(key);
+                }
+            }
+
+            DecryptMode::Totono => {
+                let table = self.totono_table();
+
+                for (index, byte) in buffer.iter_mut().enumerate().skip(start) {
+                    *byte = table[*byte as usize]
+                        .wrapping_sub(key)
+                        .wrapping_sub(index as u8);
+                }
+            }
+
+            DecryptMode::Standard => {
+                for (index, byte) in buffer.iter_mut().enumerate().skip(start) {
+                    *byte = self.table[*byte as usize]
+                        .wrapping_sub(key)
+                        .wrapping_sub(index as u8);
+                }
+            }
+        }
+    }
+}
+
+impl SchemeDecryptor {
+    /// The three substitutions and the inversion of [`DecryptMode::Totono`] composed into a single
+    /// table
+    fn totono_table(&self) -> [u8; 256] {
+        let table = &self.table;
+
+        std::array::from_fn(|byte| !table[table[table[byte] as usize] as usize])
+    }
+}
+
+/// Decrypts `buffer` with the [`DecryptMode::Standard`] scheme 16 bytes at a time
+///
+/// The table lookups are still done per byte, but both subtractions happen for a whole lane at once.
+/// Returns how many bytes were decrypted, the remaining tail is left to the scalar loop.
+#[cfg(feature = "simd")]
+pub(crate) fn decrypt_standard_simd(buffer: &mut [u8], key_table: &[u8; 256], key: u8) -> usize {
+    use wide::u8x16;
+
+    let ramp = u8x16::new(std::array::from_fn(|lane| lane as u8));
+    let key = u8x16::splat(key);
+
+    let processed = buffer.len() - buffer.len() % 16;
+
+    for (index, chunk) in buffer.chunks_exact_mut(16).enumerate() {
+        let substituted = u8x16::new(std::array::from_fn(|lane| key_table[chunk[lane] as usize]));
+        let position = u8x16::splat((index * 16) as u8) + ramp;
+
+        chunk.copy_from_slice(&(substituted - key - position).to_array());
+    }
+
+    processed
+}
@@ -0,0 +1,150 @@
+//! Errors returned while reading NPA archives
+
+use std::path::PathBuf;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum NpaError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The archive ended before the number of entries its header claims could be read
+    #[error("Header claims {expected} entries, but the archive ends after {read}")]
+    TruncatedEntryTable { expected: u32, read: usize },
+
+    /// The file doesn't start with the magic bytes of an NPA archive
+    #[error("Not an NPA archive, found magic bytes {0:02X?}")]
+    BadMagic([u8; 7]),
+
+    /// The data of a compressed entry isn't a valid zlib stream, usually because the wrong game
+    /// was picked and it didn't decrypt correctly
+    #[error("Failed to decompress entry data: {0}")]
+    DecodeFailed(#[source] std::io::Error),
+
+    /// The archive ended before all of an entry's data could be read, or with
+    /// [`SizePolicy::Strict`](crate::SizePolicy::Strict) it decompressed to a different size than
+    /// the entry table says
+    #[error("Expected {expected} bytes of entry data, but got {got}")]
+    SizeMismatch { expected: u64, got: u64 },
+
+    /// No entry in the archive has the requested path
+    #[error("\"{}\" isn't in the archive", .0.display())]
+    EntryNotFound(PathBuf),
+
+    /// None of the known games' keys decrypt the archive correctly
+    #[error("Couldn't detect which game the archive is from")]
+    UnknownGame,
+
+    /// The entry claims to decompress to more data than its compressed size can hold
+    #[error("Entry claims to decompress from {compressed} to {original} bytes")]
+    ImplausibleSize { compressed: u32, original: u32 },
+
+    /// A path can't be stored in an archive, because it isn't relative to the archive's root or
+    /// can't be encoded as Shift JIS
+    #[error("\"{}\" can't be stored in an archive", .0.display())]
+    UnencodableName(PathBuf),
+
+    /// The entry's path would place it outside of the directory it's extracted to
+    #[error("\"{}\" points outside of the output directory", .0.display())]
+    UnsafePath(PathBuf),
+
+    /// The entry's data starts before the end of the entry table, so it would be read from the
+    /// table itself
+    #[error("Entry data at {offset} overlaps the entry table, which ends at {table_end}")]
+    DataOverlapsTable { offset: u64, table_end: u64 },
+
+    /// An error that occurred while handling a specific entry
+    #[error("\"{}\": {source}", path.display())]
+    Entry {
+        path: PathBuf,
+
+        #[source]
+        source: Box<NpaError>,
+    },
+}
+
+impl NpaError {
+    /// Attaches the path of the entry that caused the error, so it shows up in the message
+    pub fn with_path(self, path: impl Into<PathBuf>) -> Self {
+        NpaError::Entry {
+            path: path.into(),
+            source: Box::new(self),
+        }
+    }
+
+    fn kind(&self) -> std::io::ErrorKind {
+        match self {
+            NpaError::Io(err) => err.kind(),
+            NpaError::SizeMismatch { .. } | NpaError::TruncatedEntryTable { .. } => {
+                std::io::ErrorKind::UnexpectedEof
+            }
+            NpaError::EntryNotFound(_) => std::io::ErrorKind::NotFound,
+            NpaError::BadMagic(_)
+            | NpaError::DecodeFailed(_)
+            | NpaError::ImplausibleSize { .. }
+            | NpaError::DataOverlapsTable { .. }
+            | NpaError::UnknownGame
+            | NpaError::UnsafePath(_) => std::io::ErrorKind::InvalidData,
+            NpaError::UnencodableName(_) => std::io::ErrorKind::InvalidInput,
+            NpaError::Entry { source, .. } => source.kind(),
+        }
+    }
+}
+
+impl From<NpaError> for std::io::Error {
+    fn from(err: NpaError) -> Self {
+        match err {
+            NpaError::Io(err) => err,
+            err => std::io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, ErrorKind};
+
+    use super::*;
+    use crate::parse_head;
+
+    #[test]
+    fn test_io_error_propagates() {
+        let err = parse_head(&mut Cursor::new([])).unwrap_err();
+
+        assert!(matches!(&err, NpaError::Io(err) if err.kind() == ErrorKind::UnexpectedEof));
+        assert_eq!(std::io::Error::from(err).kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut bytes = [0u8; crate::NPA_HEADER_SIZE as usize];
+        bytes[..7].copy_from_slice(b"PK\x03\x04\0\0\0");
+
+        let err = parse_head(&mut Cursor::new(bytes)).unwrap_err();
+
+        assert!(matches!(err, NpaError::BadMagic(magic) if magic == bytes[..7]));
+        assert_eq!(std::io::Error::from(err).kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_png_isnt_an_archive() {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(1, 1)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let err = parse_head(&mut Cursor::new(&png)).unwrap_err();
+
+        assert!(matches!(err, NpaError::BadMagic(magic) if magic == png[..7]));
+        assert!(err.to_string().starts_with("Not an NPA archive"), "{err}");
+    }
+
+    #[test]
+    fn test_display_includes_entry_path() {
+        let err = NpaError::from(std::io::Error::new(ErrorKind::InvalidData, "bad data"))
+            .with_path("scripts/op.scr");
+
+        assert_eq!(err.to_string(), "\"scripts/op.scr\": bad data");
+        assert_eq!(std::io::Error::from(err).kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,1248 @@
+//! Helpers for extracting archive entries to disk
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    ops::AddAssign,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use globset::GlobSet;
+use image::{
+    DynamicImage, ImageDecoder,
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+};
+use rayon::prelude::*;
+
+use crate::{
+    Game, GameKeys, NpaArchive, NpaEntry, NpaError, NpaHead, SizePolicy, TextEncoding, TextMode,
+    write_entry_data,
+};
+
+/// Formats that extracted images can be re-encoded to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    /// File extension used for images of this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Order in which [`select_entries`] picks entries, [`ExtractOptions::limit`] keeps the first ones
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EntryOrder {
+    /// The order of the entry table
+    #[default]
+    Table,
+
+    /// By path, ignoring case
+    Name,
+
+    /// Largest extracted size first
+    Size,
+
+    /// By where the data is stored in the archive
+    Offset,
+}
+
+/// Computes the path each entry is extracted to, relative to the output directory
+///
+/// Files whose paths only differ in case overwrite each other on case-insensitive file systems
+/// (the default on Windows and macOS), and exact duplicates overwrite each other everywhere. Each
+/// file after the first one of such a group gets its `file_id` appended to its stem, and a counter
+/// after that if the new name is taken as well, case-insensitively.
+pub fn output_paths(entries: &[NpaEntry]) -> Vec<PathBuf> {
+    let mut seen: HashMap<String, PathBuf> = HashMap::new();
+
+    entries
+        .iter()
+        .map(|entry| {
+            let path = entry.file_path.clone();
+
+            if entry.is_directory() {
+                return path;
+            }
+
+            let key = path_key(&path);
+            let Some(existing) = seen.get(&key) else {
+                seen.insert(key, path.clone());
+                return path;
+            };
+
+            if *existing == path {
+                log::warn!(
+                    "\"{}\" appears more than once in the archive",
+                    path.display()
+                );
+            } else {
+                log::warn!(
+                    "\"{}\" and \"{}\" only differ in case and would overwrite each other on a case-insensitive file system",
+                    existing.display(),
+                    path.display()
+                );
+            }
+
+            let renamed = renamed_path(&path, entry.file_id, |key| seen.contains_key(key));
+            seen.insert(path_key(&renamed), renamed.clone());
+
+            renamed
+        })
+        .collect()
+}
+
+/// Drops the directories from the path of each file, so that every file ends up directly in the
+/// output directory, and returns how many files had to be renamed
+///
+/// Names that would collide, ignoring case, get the entry's `file_id` appended to their stem like
+/// in [`output_paths`]. Directory entries are left alone, callers skip them when flattening.
+pub fn flatten_paths(entries: &[NpaEntry], paths: &mut [PathBuf]) -> usize {
+    let mut seen = HashSet::new();
+    let mut renamed = 0;
+
+    for (entry, path) in entries.iter().zip(paths) {
+        if entry.is_directory() {
+            continue;
+        }
+
+        let Some(file_name) = path.file_name() else {
+            continue;
+        };
+
+        let mut flat = PathBuf::from(file_name);
+
+        if !seen.insert(path_key(&flat)) {
+            flat = renamed_path(&flat, entry.file_id, |key| seen.contains(key));
+            seen.insert(path_key(&flat));
+            renamed += 1;
+        }
+
+        *path = flat;
+    }
+
+    renamed
+}
+
+/// `path` with `_{file_id}` appended to the stem of its file name, followed by `_2`, `_3` and so
+/// on until `is_taken` returns `false` for the [`path_key`] of the result
+///
+/// Another entry can already have the name a collision is renamed to, so it has to be checked the
+/// same way as the original name.
+fn renamed_path(path: &Path, file_id: u32, is_taken: impl Fn(&str) -> bool) -> PathBuf {
+    std::iter::once(format!("_{file_id}"))
+        .chain((2..).map(|n| format!("_{file_id}_{n}")))
+        .map(|suffix| with_suffix(path, &suffix))
+        .find(|renamed| !is_taken(&path_key(renamed)))
+        .expect("the suffixes never run out")
+}
+
+/// `path` with `suffix` appended to the stem of its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path
+        .file_stem()
+        .map(|stem| stem.to_os_string())
+        .unwrap_or_default();
+
+    file_name.push(suffix);
+
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+
+    path.with_file_name(file_name)
+}
+
+/// Checks that `path` stays inside the directory it gets joined onto, returning it without `.`
+/// components
+///
+/// Entry names come from the archive, so a crafted one like `..\..\Windows\System32\evil.dll`
+/// could otherwise write anywhere on the file system. Paths with `..`, a root or a drive prefix
+/// are rejected with [`NpaError::UnsafePath`].
+pub fn sanitize_path(path: &Path) -> Result<PathBuf, NpaError> {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| match component {
+            Component::Normal(name) => Ok(name),
+            _ => Err(NpaError::UnsafePath(path.to_path_buf())),
+        })
+        .collect()
+}
+
+/// Decodes `data` as an image and re-encodes it to `format`
+///
+/// Returns `None` if `data` isn't an image that can be decoded. `quality` (1-100) only applies to
+/// JPEG, WebP is always encoded losslessly. The orientation stored in the image's metadata, such as
+/// a JPEG's EXIF tag, is lost when re-encoding, so with `auto_orient` the pixels are rotated and
+/// flipped to match it first.
+pub fn reencode_image(
+    data: &[u8],
+    format: ImageFormat,
+    quality: u8,
+    auto_orient: bool,
+) -> Option<Vec<u8>> {
+    let mut decoder = image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+
+    let orientation = decoder.orientation();
+    let mut image = DynamicImage::from_decoder(decoder).ok()?;
+
+    if auto_orient {
+        match orientation {
+            Ok(orientation) => image.apply_orientation(orientation),
+            Err(err) => log::warn!("Failed to read the image's orientation: {err}"),
+        }
+    }
+
+    let mut output = Vec::new();
+
+    let result = match format {
+        ImageFormat::Png => image.write_with_encoder(PngEncoder::new(&mut output)),
+        ImageFormat::Jpeg => DynamicImage::from(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality)),
+        ImageFormat::Webp => DynamicImage::from(image.to_rgba8())
+            .write_with_encoder(WebPEncoder::new_lossless(&mut output)),
+    };
+
+    if let Err(err) = result {
+        log::warn!("Failed to re-encode image as {format:?}: {err}");
+        return None;
+    }
+
+    Some(output)
+}
+
+/// Calls `f` for every file in `entries` on rayon's thread pool, together with the entry's index
+/// and a reader over the archive at `path` to read the entry's data from, directories are skipped
+///
+/// Each worker thread opens its own handle to the archive, so entries are read concurrently. The
+/// first error stops the remaining entries from being processed.
+pub fn for_each_entry_parallel<F>(path: &Path, entries: &[NpaEntry], f: F) -> Result<(), NpaError>
+where
+    F: Fn(usize, &NpaEntry, &mut BufReader<File>) -> Result<(), NpaError> + Sync,
+{
+    entries
+        .par_iter()
+        .enumerate()
+        .filter(|(_, entry)| !entry.is_directory())
+        .try_for_each_init(
+            || File::open(path).map(BufReader::new),
+            |reader, (index, entry)| {
+                let reader = reader.as_mut().map_err(|err| {
+                    NpaError::from(std::io::Error::new(err.kind(), err.to_string()))
+                })?;
+
+                f(index, entry, reader)
+            },
+        )
+}
+
+/// What happens when a file that's about to be extracted already exists
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Leave the existing file alone and count it in [`ExtractReport::skipped`]
+    #[default]
+    Skip,
+
+    /// Replace the existing file
+    Replace,
+
+    /// Fail with [`std::io::ErrorKind::AlreadyExists`]
+    Error,
+}
+
+/// How [`extract_all`], [`extract_entry`] and [`extract_all_parallel`] write entries to disk
+///
+/// The fields can be set directly or through the builder methods, starting from
+/// [`ExtractOptions::new`].
+#[derive(Debug, Clone)]
+pub struct ExtractOptions {
+    /// What happens to the data of entries that are usually text
+    pub text_mode: TextMode,
+
+    /// Re-encode image entries to this format, other entries are written as is
+    pub reencode_images: Option<ImageFormat>,
+
+    /// Quality used when re-encoding images to JPEG
+    pub quality: u8,
+
+    /// Rotate re-encoded images upright according to their metadata, see [`reencode_image`]
+    pub auto_orient: bool,
+
+    /// Prefix file names with their zero-padded position in the entry table
+    pub number_prefix: bool,
+
+    /// Write every file directly into the output directory, see [`flatten_paths`]
+    pub flatten: bool,
+
+    /// What happens to files that already exist in the output directory
+    pub overwrite: Overwrite,
+
+    /// Whether entries that don't decompress to their expected size fail to extract
+    pub size_policy: SizePolicy,
+
+    /// Only entries whose path matches this are selected by [`select_entries`], matched with `/`
+    /// as the separator. Directories are then only created as the parents of matching files.
+    pub filter: Option<GlobSet>,
+
+    /// Entries whose path matches this aren't selected, even if they match
+    /// [`ExtractOptions::filter`]
+    pub exclude: Option<GlobSet>,
+
+    /// Only the first this many of the otherwise selected files are extracted, in
+    /// [`ExtractOptions::order`]. Directories are then only created as their parents.
+    pub limit: Option<usize>,
+
+    /// Order the selected entries are extracted in
+    pub order: EntryOrder,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        ExtractOptions {
+            text_mode: TextMode::default(),
+            reencode_images: None,
+            quality: 90,
+            auto_orient: true,
+            number_prefix: false,
+            flatten: false,
+            overwrite: Overwrite::default(),
+            size_policy: SizePolicy::default(),
+            filter: None,
+            exclude: None,
+            limit: None,
+            order: EntryOrder::default(),
+        }
+    }
+}
+
+impl ExtractOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text_mode(mut self, text_mode: TextMode) -> Self {
+        self.text_mode = text_mode;
+        self
+    }
+
+    /// Decodes text entries with a detected encoding, or keeps them exactly as they are stored
+    pub fn decode_text(self, decode: bool) -> Self {
+        self.text_mode(if decode {
+            TextMode::Decode(TextEncoding::Detect)
+        } else {
+            TextMode::Raw
+        })
+    }
+
+    pub fn reencode_images(mut self, format: Option<ImageFormat>) -> Self {
+        self.reencode_images = format;
+        self
+    }
+
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    pub fn auto_orient(mut self, auto_orient: bool) -> Self {
+        self.auto_orient = auto_orient;
+        self
+    }
+
+    pub fn number_prefix(mut self, number_prefix: bool) -> Self {
+        self.number_prefix = number_prefix;
+        self
+    }
+
+    pub fn flatten(mut self, flatten: bool) -> Self {
+        self.flatten = flatten;
+        self
+    }
+
+    pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    pub fn size_policy(mut self, size_policy: SizePolicy) -> Self {
+        self.size_policy = size_policy;
+        self
+    }
+
+    pub fn filter(mut self, filter: GlobSet) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn exclude(mut self, exclude: GlobSet) -> Self {
+        self.exclude = Some(exclude);
+        self
+    }
+
+    pub fn limit(mut self, limit: Option<usize>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn order(mut self, order: EntryOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Whether [`ExtractOptions::filter`] and [`ExtractOptions::exclude`] let `entry` through
+    ///
+    /// Directories are only selected when every file is, otherwise they're created as the
+    /// parents of the files that get written. They're always dropped when flattening.
+    fn selects(&self, entry: &NpaEntry) -> bool {
+        if entry.is_directory() {
+            return !self.flatten
+                && self.filter.is_none()
+                && self.exclude.is_none()
+                && self.limit.is_none();
+        }
+
+        let path: Vec<_> = entry
+            .file_path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect();
+        let path = path.join("/");
+
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.is_match(&path))
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(&path))
+    }
+}
+
+/// What was written by an extraction, reports of several extractions can be added up with `+=`
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ExtractReport {
+    /// Number of files written, directories aren't counted
+    pub files: usize,
+
+    /// Total size of the written files
+    pub bytes: u64,
+
+    /// Number of files whose text couldn't be decoded without replacing malformed bytes
+    pub decode_warnings: usize,
+
+    /// Number of files left alone because they already existed
+    pub skipped: usize,
+
+    /// Number of files whose names collided after flattening and were renamed, see
+    /// [`flatten_paths`]
+    pub renamed: usize,
+}
+
+impl AddAssign for ExtractReport {
+    fn add_assign(&mut self, other: Self) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+        self.decode_warnings += other.decode_warnings;
+        self.skipped += other.skipped;
+        self.renamed += other.renamed;
+    }
+}
+
+/// Prefixes the file name of each path with the zero-padded index of its entry, directories are
+/// left alone
+pub fn add_number_prefixes(entries: &[NpaEntry], paths: &mut [PathBuf]) {
+    let width = entries.len().to_string().len();
+
+    for (index, (entry, path)) in entries.iter().zip(paths).enumerate() {
+        if let Some(file_name) = path.file_name().filter(|_| !entry.is_directory()) {
+            let mut prefixed = OsString::from(format!("{index:0width$}_"));
+            prefixed.push(file_name);
+            path.set_file_name(prefixed);
+        }
+    }
+}
+
+/// Writes `entry` to `relative` inside `output_directory`, creating directory entries and the
+/// parents of files as needed
+///
+/// `relative` is checked with [`sanitize_path`] first. Errors carry the entry's path.
+pub fn extract_entry<R: Read + Seek, G: GameKeys>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    output_directory: &Path,
+    relative: &Path,
+    game: G,
+    options: &ExtractOptions,
+) -> Result<ExtractReport, NpaError> {
+    let archive_len = reader
+        .seek(SeekFrom::End(0))
+        .map_err(|err| NpaError::from(err).with_path(&entry.file_path))?;
+    let path = output_path(entry, output_directory, relative)?;
+
+    extract_to(reader, header, entry, &path, game, options, archive_len)
+}
+
+/// `relative` inside `output_directory`, after checking it with [`sanitize_path`]
+pub(crate) fn output_path(
+    entry: &NpaEntry,
+    output_directory: &Path,
+    relative: &Path,
+) -> Result<PathBuf, NpaError> {
+    sanitize_path(relative)
+        .map(|relative| output_directory.join(relative))
+        .map_err(|err| err.with_path(&entry.file_path))
+}
+
+/// [`extract_entry`] to a `path` from [`output_path`], in an archive of `archive_len` bytes
+pub(crate) fn extract_to<R: Read + Seek, G: GameKeys>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    path: &Path,
+    game: G,
+    options: &ExtractOptions,
+    archive_len: u64,
+) -> Result<ExtractReport, NpaError> {
+    write_to_disk(reader, header, entry, path, game, options, archive_len)
+        .map_err(|err| err.with_path(&entry.file_path))
+}
+
+fn write_to_disk<R: Read + Seek, G: GameKeys>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    path: &Path,
+    game: G,
+    options: &ExtractOptions,
+    archive_len: u64,
+) -> Result<ExtractReport, NpaError> {
+    if entry.is_directory() {
+        fs::create_dir_all(path)?;
+        return Ok(ExtractReport::default());
+    }
+
+    let exists = path.exists()
+        || options
+            .reencode_images
+            .is_some_and(|format| path.with_extension(format.extension()).exists());
+
+    if exists {
+        match options.overwrite {
+            Overwrite::Skip => {
+                return Ok(ExtractReport {
+                    skipped: 1,
+                    ..Default::default()
+                });
+            }
+            Overwrite::Replace => {}
+            Overwrite::Error => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("\"{}\" already exists", path.display()),
+                )
+                .into());
+            }
+        }
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let text_mode = options.text_mode;
+
+    let Some(format) = options.reencode_images else {
+        let mut out = BufWriter::new(File::create(path)?);
+        let written = write_entry_data(
+            reader,
+            header,
+            entry,
+            &game,
+            text_mode,
+            options.size_policy,
+            archive_len,
+            &mut out,
+        )
+        .and_then(|written| Ok(out.flush().map(|_| written)?));
+
+        drop(out);
+
+        // Don't leave a truncated file behind that would be skipped the next time
+        let (bytes, lossy) = written.inspect_err(|_| {
+            let _ = fs::remove_file(path);
+        })?;
+
+        return Ok(ExtractReport {
+            files: 1,
+            bytes,
+            decode_warnings: lossy as usize,
+            ..Default::default()
+        });
+    };
+
+    let mut data = Vec::new();
+    let (_, lossy) = write_entry_data(
+        reader,
+        header,
+        entry,
+        &game,
+        text_mode,
+        options.size_policy,
+        archive_len,
+        &mut data,
+    )?;
+
+    let bytes = match reencode_image(&data, format, options.quality, options.auto_orient) {
+        Some(image) => {
+            fs::write(path.with_extension(format.extension()), &image)?;
+            image.len()
+        }
+        None => {
+            fs::write(path, &data)?;
+            data.len()
+        }
+    };
+
+    Ok(ExtractReport {
+        files: 1,
+        bytes: bytes as u64,
+        decode_warnings: lossy as usize,
+        ..Default::default()
+    })
+}
+
+/// The entries of the archive with `header` that [`extract_all`] and [`extract_all_parallel`]
+/// write with `options`, in [`ExtractOptions::order`] and together with the path each one is
+/// written to inside the output directory
+///
+/// Paths come from [`output_paths`], then [`flatten_paths`] and [`add_number_prefixes`] if
+/// `options` asks for them, so they don't depend on the order. They aren't checked with
+/// [`sanitize_path`] yet, extracting does that.
+pub fn select_entries<'a>(
+    header: &NpaHead,
+    entries: &'a [NpaEntry],
+    options: &ExtractOptions,
+) -> Vec<(&'a NpaEntry, PathBuf)> {
+    select(header, entries, options).0
+}
+
+/// [`select_entries`], together with how many files [`flatten_paths`] renamed
+fn select<'a>(
+    header: &NpaHead,
+    entries: &'a [NpaEntry],
+    options: &ExtractOptions,
+) -> (Vec<(&'a NpaEntry, PathBuf)>, usize) {
+    let mut paths = output_paths(entries);
+    let renamed = if options.flatten {
+        flatten_paths(entries, &mut paths)
+    } else {
+        0
+    };
+
+    if options.number_prefix {
+        add_number_prefixes(entries, &mut paths);
+    }
+
+    let mut selected: Vec<_> = entries
+        .iter()
+        .zip(paths)
+        .filter(|(entry, _)| options.selects(entry))
+        .collect();
+
+    // Stable, so that entries that compare equal stay in table order
+    match options.order {
+        EntryOrder::Table => {}
+        EntryOrder::Name => selected.sort_by_cached_key(|(entry, _)| path_key(&entry.file_path)),
+        EntryOrder::Size => selected.sort_by_key(|(entry, _)| {
+            // Archives that aren't compressed don't always fill in the original size
+            let size = if header.compressed {
+                entry.original_size
+            } else {
+                entry.compressed_size
+            };
+
+            std::cmp::Reverse(size)
+        }),
+        EntryOrder::Offset => selected.sort_by_key(|(entry, _)| entry.offset),
+    }
+
+    selected.truncate(options.limit.unwrap_or(usize::MAX));
+
+    (selected, renamed)
+}
+
+/// Extracts the entries picked by [`select_entries`] into `output_directory`, one after another,
+/// stopping at the first one that fails
+///
+/// Output paths are computed with [`output_paths`], so colliding names don't overwrite each other.
+pub fn extract_all<R: Read + Seek, G: GameKeys>(
+    reader: &mut R,
+    header: &NpaHead,
+    entries: &[NpaEntry],
+    output_directory: &Path,
+    game: G,
+    options: &ExtractOptions,
+) -> Result<ExtractReport, NpaError> {
+    extract_all_with_progress(
+        reader,
+        header,
+        entries,
+        output_directory,
+        game,
+        options,
+        |_, result| result.map(|_| ()),
+    )
+}
+
+/// Same as [`extract_all`], but passes the result of every file to `on_file` once it's written
+///
+/// A file that fails to extract only stops the others if `on_file` returns its error, the
+/// returned report counts the files that succeeded. Failing to create a directory always stops.
+pub fn extract_all_with_progress<R, G, F>(
+    reader: &mut R,
+    header: &NpaHead,
+    entries: &[NpaEntry],
+    output_directory: &Path,
+    game: G,
+    options: &ExtractOptions,
+    mut on_file: F,
+) -> Result<ExtractReport, NpaError>
+where
+    R: Read + Seek,
+    G: GameKeys,
+    F: FnMut(&NpaEntry, Result<ExtractReport, NpaError>) -> Result<(), NpaError>,
+{
+    let (selected, renamed) = select(header, entries, options);
+    let mut report = ExtractReport {
+        renamed,
+        ..Default::default()
+    };
+
+    let archive_len = reader.seek(SeekFrom::End(0))?;
+
+    for (entry, relative) in selected {
+        let result = output_path(entry, output_directory, &relative)
+            .and_then(|path| extract_to(reader, header, entry, &path, &game, options, archive_len));
+
+        if entry.is_directory() {
+            result?;
+            continue;
+        }
+
+        if let Ok(written) = &result {
+            report += *written;
+        }
+
+        on_file(entry, result)?;
+    }
+
+    Ok(report)
+}
+
+/// Extracts the entries of the archive at `path` picked by [`select_entries`] into
+/// `output_directory` using all cores
+///
+/// Same as [`extract_all`] otherwise, only the order in which files are written differs.
+pub fn extract_all_parallel(
+    path: &Path,
+    output_directory: &Path,
+    game: Game,
+    options: &ExtractOptions,
+) -> Result<ExtractReport, NpaError> {
+    let archive = NpaArchive::open(BufReader::new(File::open(path)?), game)?;
+
+    extract_all_parallel_with_progress(
+        path,
+        archive.header(),
+        archive.entries(),
+        output_directory,
+        game,
+        options,
+        |_, result| result.map(|_| ()),
+    )
+}
+
+/// Same as [`extract_all_parallel`] for an archive whose entry table has already been read, passing
+/// the result of every file to `on_file` like [`extract_all_with_progress`]
+///
+/// `on_file` is called from whichever thread wrote the file. Calling this inside
+/// [`rayon::ThreadPool::install`] picks how many threads are used.
+pub fn extract_all_parallel_with_progress<G, F>(
+    path: &Path,
+    header: &NpaHead,
+    entries: &[NpaEntry],
+    output_directory: &Path,
+    game: G,
+    options: &ExtractOptions,
+    on_file: F,
+) -> Result<ExtractReport, NpaError>
+where
+    G: GameKeys + Sync,
+    F: Fn(&NpaEntry, Result<ExtractReport, NpaError>) -> Result<(), NpaError> + Sync,
+{
+    let (selected, renamed) = select(header, entries, options);
+    let archive_len = fs::metadata(path)?.len();
+    let (directories, files): (Vec<_>, Vec<_>) = selected
+        .into_iter()
+        .partition(|(entry, _)| entry.is_directory());
+
+    for (entry, relative) in directories {
+        fs::create_dir_all(output_path(entry, output_directory, &relative)?)?;
+    }
+
+    let (files, paths): (Vec<NpaEntry>, Vec<PathBuf>) = files
+        .into_iter()
+        .map(|(entry, relative)| (entry.clone(), relative))
+        .unzip();
+
+    let report = Mutex::new(ExtractReport {
+        renamed,
+        ..Default::default()
+    });
+
+    for_each_entry_parallel(path, &files, |index, entry, reader| {
+        let result = output_path(entry, output_directory, &paths[index])
+            .and_then(|path| extract_to(reader, header, entry, &path, &game, options, archive_len));
+
+        if let Ok(written) = &result {
+            *report.lock().expect("report lock poisoned") += *written;
+        }
+
+        on_file(entry, result)
+    })?;
+
+    Ok(report.into_inner().expect("report lock poisoned"))
+}
+
+fn path_key(path: &std::path::Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_lowercase())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, file_id: u32) -> NpaEntry {
+        NpaEntry {
+            file_id,
+            file_path: PathBuf::from(path),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_output_paths_renames_case_insensitive_collisions() {
+        let entries = [
+            entry("sys/Data.dat", 0),
+            entry("sys/data.dat", 1),
+            entry("SYS/DATA.DAT", 2),
+            entry("sys/other.dat", 3),
+        ];
+
+        assert_eq!(
+            output_paths(&entries),
+            [
+                PathBuf::from("sys/Data.dat"),
+                PathBuf::from("sys/data_1.dat"),
+                PathBuf::from("SYS/DATA_2.DAT"),
+                PathBuf::from("sys/other.dat"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_paths_checks_renamed_names() {
+        let entries = [
+            entry("sys/Data.dat", 1),
+            entry("sys/data.dat", 1),
+            entry("sys/DATA_1.dat", 2),
+            entry("sys/data_1.dat", 3),
+        ];
+
+        assert_eq!(
+            output_paths(&entries),
+            [
+                PathBuf::from("sys/Data.dat"),
+                PathBuf::from("sys/data_1.dat"),
+                PathBuf::from("sys/DATA_1_2.dat"),
+                PathBuf::from("sys/data_1_3.dat"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flatten_paths() {
+        let entries = [
+            entry("bg/title.png", 0),
+            entry("ev/Title.png", 1),
+            entry("ev/cg01.png", 2),
+        ];
+        let mut paths = output_paths(&entries);
+
+        assert_eq!(flatten_paths(&entries, &mut paths), 1);
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("title.png"),
+                PathBuf::from("Title_1.png"),
+                PathBuf::from("cg01.png"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flatten_paths_checks_renamed_names() {
+        let entries = [
+            entry("a/img.png", 3),
+            entry("b/img.png", 3),
+            entry("c/img_3.png", 4),
+            entry("d/img.png", 3),
+        ];
+        let mut paths = output_paths(&entries);
+
+        assert_eq!(flatten_paths(&entries, &mut paths), 3);
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("img.png"),
+                PathBuf::from("img_3.png"),
+                PathBuf::from("img_3_4.png"),
+                PathBuf::from("img_3_2.png"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reencode_image() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgba8(4, 4)
+            .write_with_encoder(PngEncoder::new(&mut png))
+            .unwrap();
+
+        for (format, expected) in [
+            (ImageFormat::Png, image::ImageFormat::Png),
+            (ImageFormat::Jpeg, image::ImageFormat::Jpeg),
+            (ImageFormat::Webp, image::ImageFormat::WebP),
+        ] {
+            let encoded = reencode_image(&png, format, 80, true).unwrap();
+            assert_eq!(image::guess_format(&encoded).unwrap(), expected);
+        }
+
+        assert!(reencode_image(b"not an image", ImageFormat::Png, 80, true).is_none());
+    }
+
+    #[test]
+    fn test_reencode_image_applies_orientation() {
+        use image::ImageEncoder;
+
+        // Little endian TIFF header and a single IFD entry: orientation (0x0112), a SHORT, 6
+        let exif = [
+            b"II*\0\x08\0\0\0\x01\0".as_slice(),
+            &[
+                0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00,
+            ],
+            &[0; 4],
+        ]
+        .concat();
+
+        let mut png = Vec::new();
+        let mut encoder = PngEncoder::new(&mut png);
+        encoder.set_exif_metadata(exif).unwrap();
+        encoder
+            .write_image(&[0; 4 * 2 * 4], 4, 2, image::ExtendedColorType::Rgba8)
+            .unwrap();
+
+        let dimensions = |auto_orient| {
+            let encoded = reencode_image(&png, ImageFormat::Png, 80, auto_orient).unwrap();
+            let image = image::load_from_memory(&encoded).unwrap();
+            (image.width(), image.height())
+        };
+
+        assert_eq!(dimensions(true), (2, 4));
+        assert_eq!(dimensions(false), (4, 2));
+    }
+
+    #[test]
+    fn test_extract_all_parallel() {
+        let directory = std::env::temp_dir().join(format!("cat-nipa-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let archive = crate::tests::build_archive(&[
+            ("a.txt", b"first"),
+            ("sub\\b.txt", b"second"),
+            ("A.txt", b"third"),
+        ]);
+        let archive_path = directory.join("test.npa");
+        fs::write(&archive_path, archive).unwrap();
+
+        let output = directory.join("out");
+        let report =
+            extract_all_parallel(&archive_path, &output, Game::ChaosHead, &Default::default())
+                .unwrap();
+
+        assert_eq!(
+            report,
+            ExtractReport {
+                files: 3,
+                bytes: 16,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(fs::read(output.join("a.txt")).unwrap(), b"first");
+        assert_eq!(fs::read(output.join("sub/b.txt")).unwrap(), b"second");
+        assert_eq!(fs::read(output.join("A_2.txt")).unwrap(), b"third");
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_extract_all() {
+        let directory =
+            std::env::temp_dir().join(format!("cat-nipa-serial-{}", std::process::id()));
+
+        let archive = crate::tests::build_archive(&[("a.txt", b"first"), ("sub\\b.dat", b"2")]);
+        let mut reader = std::io::Cursor::new(archive);
+        let header = crate::parse_head(&mut reader).unwrap();
+        let entries = crate::read_entries(&mut reader, &header, false).unwrap();
+
+        let options = ExtractOptions {
+            text_mode: TextMode::Raw,
+            number_prefix: true,
+            ..Default::default()
+        };
+        let report = extract_all(
+            &mut reader,
+            &header,
+            &entries,
+            &directory,
+            Game::ChaosHead,
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(report.files, 2);
+        assert_eq!(report.bytes, 6);
+        assert_eq!(fs::read(directory.join("0_a.txt")).unwrap(), b"first");
+        assert_eq!(fs::read(directory.join("sub/1_b.dat")).unwrap(), b"2");
+
+        fs::write(directory.join("0_a.txt"), b"edited").unwrap();
+
+        let extract_again = |reader: &mut std::io::Cursor<Vec<u8>>, options: &ExtractOptions| {
+            extract_all(
+                reader,
+                &header,
+                &entries,
+                &directory,
+                Game::ChaosHead,
+                options,
+            )
+            .unwrap()
+        };
+
+        let report = extract_again(&mut reader, &options);
+        assert_eq!((report.files, report.skipped), (0, 2));
+        assert_eq!(fs::read(directory.join("0_a.txt")).unwrap(), b"edited");
+
+        let options = options.overwrite(Overwrite::Replace);
+        let report = extract_again(&mut reader, &options);
+        assert_eq!((report.files, report.skipped), (2, 0));
+        assert_eq!(fs::read(directory.join("0_a.txt")).unwrap(), b"first");
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_extract_all_with_filter() {
+        let directory =
+            std::env::temp_dir().join(format!("cat-nipa-filter-{}", std::process::id()));
+
+        let archive =
+            crate::tests::build_archive(&[("bg\\title.png", b"png"), ("script\\op.nss", b"op")]);
+        let mut reader = std::io::Cursor::new(archive);
+        let header = crate::parse_head(&mut reader).unwrap();
+        let entries = crate::read_entries(&mut reader, &header, false).unwrap();
+
+        let filter = globset::GlobSet::new([globset::Glob::new("script/*").unwrap()]).unwrap();
+        let options = ExtractOptions::new()
+            .decode_text(false)
+            .filter(filter)
+            .overwrite(Overwrite::Error);
+
+        let mut extract = || {
+            extract_all(
+                &mut reader,
+                &header,
+                &entries,
+                &directory,
+                Game::ChaosHead,
+                &options,
+            )
+        };
+
+        assert_eq!(extract().unwrap().files, 1);
+        assert_eq!(fs::read(directory.join("script/op.nss")).unwrap(), b"op");
+        assert!(!directory.join("bg").exists());
+
+        let err = extract().unwrap_err();
+        assert_eq!(
+            std::io::Error::from(err).kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_select_entries() {
+        let directory = NpaEntry {
+            type_: 1,
+            ..entry("bg", 0)
+        };
+        let sized = |path, file_id, compressed_size| NpaEntry {
+            compressed_size,
+            ..entry(path, file_id)
+        };
+        let entries = [
+            directory,
+            sized("bg/a.png", 1, 10),
+            sized("bg/b.png", 2, 5),
+            sized("script/op.nss", 3, 20),
+        ];
+        let archive = crate::tests::build_archive(&[]);
+        let header = crate::parse_head(&mut std::io::Cursor::new(archive)).unwrap();
+        let selected = |options: &ExtractOptions| -> Vec<PathBuf> {
+            select_entries(&header, &entries, options)
+                .into_iter()
+                .map(|(_, path)| path)
+                .collect()
+        };
+
+        assert_eq!(selected(&ExtractOptions::new()).len(), 4);
+
+        let exclude = globset::GlobSet::new([globset::Glob::new("*.nss").unwrap()]).unwrap();
+        assert_eq!(
+            selected(&ExtractOptions::new().exclude(exclude).number_prefix(true)),
+            [PathBuf::from("bg/1_a.png"), PathBuf::from("bg/2_b.png")]
+        );
+
+        assert_eq!(
+            selected(&ExtractOptions::new().limit(Some(2))),
+            [PathBuf::from("bg/a.png"), PathBuf::from("bg/b.png")]
+        );
+        let biggest = ExtractOptions::new().order(EntryOrder::Size).limit(Some(2));
+        assert_eq!(
+            selected(&biggest),
+            [PathBuf::from("script/op.nss"), PathBuf::from("bg/a.png")]
+        );
+
+        let by_name = ExtractOptions::new().order(EntryOrder::Name);
+        assert_eq!(
+            selected(&by_name),
+            ["bg", "bg/a.png", "bg/b.png", "script/op.nss"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_extract_all_with_progress_keeps_going() {
+        let directory =
+            std::env::temp_dir().join(format!("cat-nipa-progress-{}", std::process::id()));
+
+        let archive =
+            crate::tests::build_archive(&[("..\\evil.txt", b"gotcha"), ("good.txt", b"good")]);
+        let mut reader = std::io::Cursor::new(archive);
+        let header = crate::parse_head(&mut reader).unwrap();
+        let entries = crate::read_entries(&mut reader, &header, false).unwrap();
+
+        let mut failed = Vec::new();
+        let report = extract_all_with_progress(
+            &mut reader,
+            &header,
+            &entries,
+            &directory,
+            Game::ChaosHead,
+            &ExtractOptions::new().decode_text(false),
+            |entry, result| {
+                if result.is_err() {
+                    failed.push(entry.file_path.clone());
+                }
+
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(report.files, 1);
+        assert_eq!(failed, [PathBuf::from("../evil.txt")]);
+        assert_eq!(fs::read(directory.join("good.txt")).unwrap(), b"good");
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(
+            sanitize_path(Path::new("./bg/./title.png")).unwrap(),
+            PathBuf::from("bg/title.png")
+        );
+
+        for path in ["../evil.txt", "bg/../../evil.txt", "/etc/passwd"] {
+            let err = sanitize_path(Path::new(path)).unwrap_err();
+            assert!(matches!(err, NpaError::UnsafePath(_)), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_extract_all_parallel_rejects_traversal() {
+        let directory =
+            std::env::temp_dir().join(format!("cat-nipa-traversal-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let archive = crate::tests::build_archive(&[("..\\..\\evil.txt", b"gotcha")]);
+        let archive_path = directory.join("test.npa");
+        fs::write(&archive_path, archive).unwrap();
+
+        let output = directory.join("a/b/out");
+        let err =
+            extract_all_parallel(&archive_path, &output, Game::ChaosHead, &Default::default())
+                .unwrap_err();
+
+        assert!(matches!(
+            err,
+            NpaError::Entry { source, .. } if matches!(*source, NpaError::UnsafePath(_))
+        ));
+        assert!(!directory.join("a/evil.txt").exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_output_paths_renames_exact_duplicates() {
+        let entries = [entry("a.txt", 4), entry("a.txt", 7)];
+
+        assert_eq!(
+            output_paths(&entries),
+            [PathBuf::from("a.txt"), PathBuf::from("a_7.txt")]
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! Read-only FUSE view of an archive
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use cat_nipa::{Game, NpaArchive, NpaEntry};
+use fuser::{
+    Config, Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags, Generation, INodeNo,
+    LockOwner, MountOption, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen,
+    Request,
+};
+
+const TTL: Duration = Duration::from_secs(60);
+
+/// A file or directory of the mounted archive, its inode number is its index plus one
+struct Node {
+    parent: INodeNo,
+    name: OsString,
+
+    /// Index into the archive's entries, `None` for directories
+    entry: Option<usize>,
+
+    /// Size of the entry's data once decompressed
+    size: u64,
+
+    children: Vec<INodeNo>,
+}
+
+struct ArchiveFs {
+    game: Game,
+    nodes: Vec<Node>,
+    archive: Mutex<NpaArchive<BufReader<File>>>,
+
+    /// Data of the most recently read entry, so that reading a file in chunks only decrypts it once
+    last_read: Mutex<Option<(usize, Arc<Vec<u8>>)>>,
+}
+
+/// Mounts the archive read-only at `mountpoint`, blocking until it is unmounted
+pub fn mount(
+    archive: NpaArchive<BufReader<File>>,
+    game: Game,
+    source: &Path,
+    mountpoint: &Path,
+) -> std::io::Result<()> {
+    let filesystem = ArchiveFs {
+        game,
+        nodes: build_nodes(archive.entries(), archive.header().compressed),
+        archive: Mutex::new(archive),
+        last_read: Mutex::new(None),
+    };
+
+    let mut config = Config::default();
+    config.mount_options = vec![
+        MountOption::RO,
+        MountOption::FSName(source.display().to_string()),
+        MountOption::Subtype(String::from("npa")),
+    ];
+
+    fuser::mount(filesystem, mountpoint, &config)
+}
+
+fn build_nodes(entries: &[NpaEntry], compressed: bool) -> Vec<Node> {
+    let mut nodes = vec![Node {
+        parent: INodeNo::ROOT,
+        name: OsString::new(),
+        entry: None,
+        size: 0,
+        children: Vec::new(),
+    }];
+
+    let mut lookup: HashMap<(INodeNo, OsString), INodeNo> = HashMap::new();
+
+    for (index, entry) in entries.iter().enumerate() {
+        let mut parent = INodeNo::ROOT;
+        let mut components = entry.file_path.components().peekable();
+
+        while let Some(component) = components.next() {
+            let name = component.as_os_str().to_os_string();
+            let is_last = components.peek().is_none();
+
+            let ino = *lookup.entry((parent, name.clone())).or_insert_with(|| {
+                let ino = INodeNo(nodes.len() as u64 + 1);
+
+                nodes.push(Node {
+                    parent,
+                    name,
+                    entry: None,
+                    size: 0,
+                    children: Vec::new(),
+                });
+
+                nodes[parent.0 as usize - 1].children.push(ino);
+
+                ino
+            });
+
+            if is_last && !entry.is_directory() {
+                let node = &mut nodes[ino.0 as usize - 1];
+
+                node.entry = Some(index);
+                node.size = if compressed {
+                    entry.original_size
+                } else {
+                    entry.compressed_size
+                } as u64;
+            }
+
+            parent = ino;
+        }
+    }
+
+    nodes
+}
+
+impl ArchiveFs {
+    fn node(&self, ino: INodeNo) -> Option<&Node> {
+        (ino.0 as usize)
+            .checked_sub(1)
+            .and_then(|i| self.nodes.get(i))
+    }
+
+    fn attr(&self, ino: INodeNo, node: &Node) -> FileAttr {
+        let (kind, perm, size) = match node.entry {
+            Some(_) => (FileType::RegularFile, 0o444, node.size),
+            None => (FileType::Directory, 0o555, 0),
+        };
+
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    fn data(&self, index: usize) -> std::io::Result<Arc<Vec<u8>>> {
+        let mut last_read = self.last_read.lock().expect("cache lock poisoned");
+
+        if let Some((cached, data)) = last_read.as_ref()
+            && *cached == index
+        {
+            return Ok(Arc::clone(data));
+        }
+
+        let mut archive = self.archive.lock().expect("archive lock poisoned");
+        let entry = archive.entries()[index].clone();
+        let data = Arc::new(archive.read(&entry, self.game).inspect_err(|err| {
+            log::error!("Failed to read \"{}\": {err}", entry.file_path.display())
+        })?);
+
+        *last_read = Some((index, Arc::clone(&data)));
+
+        Ok(data)
+    }
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        let found = self.node(parent).and_then(|node| {
+            node.children
+                .iter()
+                .copied()
+                .find(|&child| self.nodes[child.0 as usize - 1].name == name)
+        });
+
+        match found {
+            Some(ino) => reply.entry(
+                &TTL,
+                &self.attr(ino, &self.nodes[ino.0 as usize - 1]),
+                Generation(0),
+            ),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        match self.node(ino) {
+            Some(node) => reply.attr(&TTL, &self.attr(ino, node)),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn open(&self, _req: &Request, ino: INodeNo, _flags: OpenFlags, reply: ReplyOpen) {
+        match self.node(ino) {
+            // The decoded size of text entries can differ from the size stored in the archive, so
+            // reads shouldn't be cut off at the reported size
+            Some(node) if node.entry.is_some() => {
+                reply.opened(FileHandle(0), FopenFlags::FOPEN_DIRECT_IO)
+            }
+            Some(_) => reply.error(Errno::EISDIR),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        let Some(index) = self.node(ino).and_then(|node| node.entry) else {
+            reply.error(Errno::ENOENT);
+            return;
+        };
+
+        match self.data(index) {
+            Ok(data) => {
+                let start = (offset as usize).min(data.len());
+                let end = start.saturating_add(size as usize).min(data.len());
+
+                reply.data(&data[start..end]);
+            }
+            Err(_) => reply.error(Errno::EIO),
+        }
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(node) = self.node(ino) else {
+            reply.error(Errno::ENOENT);
+            return;
+        };
+
+        let listing = [
+            (ino, FileType::Directory, OsStr::new(".")),
+            (node.parent, FileType::Directory, OsStr::new("..")),
+        ]
+        .into_iter()
+        .chain(node.children.iter().map(|&child| {
+            let child_node = &self.nodes[child.0 as usize - 1];
+            let kind = match child_node.entry {
+                Some(_) => FileType::RegularFile,
+                None => FileType::Directory,
+            };
+
+            (child, kind, child_node.name.as_os_str())
+        }));
+
+        for (i, (ino, kind, name)) in listing.enumerate().skip(offset as usize) {
+            if reply.add(ino, i as u64 + 1, kind, name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+}
@@ -1,51 +1,149 @@
 use std::{
-    io::{Read, Seek, SeekFrom},
-    path::PathBuf,
+    ffi::OsStr,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
-use crypt::{decrypt_data, decrypt_header};
+pub use crypt::{Decryptor, SchemeDecryptor, decrypt_data, decrypt_header};
 use crypt_keys::*;
+pub use error::NpaError;
+pub use extract::{
+    EntryOrder, ExtractOptions, ExtractReport, ImageFormat, Overwrite, add_number_prefixes,
+    extract_all, extract_all_parallel, extract_all_parallel_with_progress,
+    extract_all_with_progress, extract_entry, flatten_paths, for_each_entry_parallel, output_paths,
+    reencode_image, sanitize_path, select_entries,
+};
 use flate2::read::ZlibDecoder;
 use log::debug;
+pub use manifest::{ManifestEntry, Mismatch, compare_manifests, read_manifest, write_manifest};
+use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
+pub use tree::{NpaNode, build_tree};
 use util::{read_u8, read_u32_le};
+pub use write::NpaWriter;
 
+pub mod crypt;
 pub mod crypt_keys;
+pub mod error;
+pub mod extract;
+pub mod manifest;
+pub mod tree;
+pub mod write;
 
-mod crypt;
 mod util;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, EnumIter)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
 #[non_exhaustive]
 pub enum Game {
+    /// Chaos;Head
     ChaosHead,
+    /// Chaos;Head Trial 1
     ChaosHeadTrailOne,
+    /// Chaos;Head Trial 2
     ChaosHeadTrailTwo,
+    /// Full Metal Daemon Muramasa Trial
     MuramasaTrail,
+    /// Full Metal Daemon Muramasa
     Muramasa,
+    /// Sumaga
     Sumaga,
+    /// Zoku Satsuriku no Django
     Django,
+    /// Zoku Satsuriku no Django Trial
     DjangoTrial,
+    /// Lamento -Beyond the Void-
     Lamento,
+    /// Lamento -Beyond the Void- Trial
     LamentoTrail,
+    /// Sweet Pool
     SweetPool,
+    /// Sumaga Special
     SumagaSpecial,
+    /// Demonbane The Best
     Demonbane,
+    /// Full Metal Daemon Muramasa AD
+    #[cfg_attr(feature = "serde", serde(rename = "muramasa-ad"))]
     MuramasaAD,
+    /// Axanael Trial
     Axanael,
+    /// Kikokugai N2System
     Kikokugai,
+    /// Sonicomi Trial 2
     SonicomiTrialTwo,
+    /// Sumaga 3% Trial
     SumagaThreePercent,
+    /// Sonicomi
     Sonicomi,
+    /// Guilty Crown Lost Xmas
     LostX,
+    /// Guilty Crown Lost Xmas Trailer
     LostXTrailer,
+    /// DRAMAtical Murder
     DramaticalMurder,
+    /// Totono
     Totono,
+    /// DRAMAtical Murder re:connect
     DramaticalMurderReConnect,
+    /// Full Metal Daemon Muramasa SS
+    #[cfg_attr(feature = "serde", serde(rename = "muramasa-ss"))]
     MuramasaSS,
 }
 
+impl std::fmt::Display for Game {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.title())
+    }
+}
+
 impl Game {
+    /// Title of the game as it's written on its cover, which is also what [`Display`] prints
+    ///
+    /// [`Display`]: std::fmt::Display
+    pub fn title(self) -> &'static str {
+        match self {
+            Game::ChaosHead => "Chaos;Head",
+            Game::ChaosHeadTrailOne => "Chaos;Head Trial 1",
+            Game::ChaosHeadTrailTwo => "Chaos;Head Trial 2",
+            Game::MuramasaTrail => "Full Metal Daemon Muramasa Trial",
+            Game::Muramasa => "Full Metal Daemon Muramasa",
+            Game::Sumaga => "Sumaga",
+            Game::Django => "Zoku Satsuriku no Django",
+            Game::DjangoTrial => "Zoku Satsuriku no Django Trial",
+            Game::Lamento => "Lamento -Beyond the Void-",
+            Game::LamentoTrail => "Lamento -Beyond the Void- Trial",
+            Game::SweetPool => "Sweet Pool",
+            Game::SumagaSpecial => "Sumaga Special",
+            Game::Demonbane => "Demonbane The Best",
+            Game::MuramasaAD => "Full Metal Daemon Muramasa AD",
+            Game::Axanael => "Axanael Trial",
+            Game::Kikokugai => "Kikokugai N2System",
+            Game::SonicomiTrialTwo => "Sonicomi Trial 2",
+            Game::SumagaThreePercent => "Sumaga 3% Trial",
+            Game::Sonicomi => "Sonicomi",
+            Game::LostX => "Guilty Crown Lost Xmas",
+            Game::LostXTrailer => "Guilty Crown Lost Xmas Trailer",
+            Game::DramaticalMurder => "DRAMAtical Murder",
+            Game::Totono => "Totono",
+            Game::DramaticalMurderReConnect => "DRAMAtical Murder re:connect",
+            Game::MuramasaSS => "Full Metal Daemon Muramasa SS",
+        }
+    }
+
+    /// Scheme the data of this game's archives is encrypted with, see [`DecryptMode::games`] for
+    /// the other way around
+    pub fn scheme(self) -> DecryptMode {
+        match self {
+            Game::Lamento | Game::LamentoTrail => DecryptMode::Lamento,
+            Game::Totono => DecryptMode::Totono,
+            _ => DecryptMode::Standard,
+        }
+    }
+
     pub fn encryption_key(self) -> [u8; 256] {
         match self {
             Game::ChaosHead => CHAOS_HEAD,
@@ -75,11 +173,291 @@ impl Game {
             Game::MuramasaSS => MURAMASA_SS,
         }
     }
+
+    /// Games that could have written an archive with this header, most likely first
+    ///
+    /// Every known game writes the same `NPA\x01\0\0\0` magic, they only differ in the parameters
+    /// their data is decrypted with, so telling them apart takes trial decryption, see
+    /// [`detect_game`]. This only narrows down which games are worth trying:
+    ///
+    /// - Unencrypted archives read the same with every game, so only the first one is returned.
+    /// - Otherwise every game is a candidate, except that of games sharing the same decryption
+    ///   parameters (such as [`Game::Lamento`] and [`Game::LamentoTrail`]) only the first is kept,
+    ///   as trying the others would give the same result.
+    ///
+    /// Neither the `compressed` flag nor the header keys narrow the list down: every scheme decrypts
+    /// before decompressing, and no known game writes fixed keys that would identify it.
+    pub fn candidates(header: &NpaHead) -> Vec<Game> {
+        if !header.encrypted {
+            return Game::iter().take(1).collect();
+        }
+
+        let mut seen: Vec<CustomGame> = Vec::new();
+
+        Game::iter()
+            .filter(|&game| {
+                let keys = CustomGame::from(game);
+                let is_new = !seen.contains(&keys);
+
+                if is_new {
+                    seen.push(keys);
+                }
+
+                is_new
+            })
+            .collect()
+    }
+}
+
+/// How the data of an archive's entries is decrypted
+///
+/// Games using the same scheme only differ in their key table and entry key seed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumIter)]
+pub enum DecryptMode {
+    /// Each byte is substituted through the key table, then the entry key and the byte's position
+    /// are subtracted from it
+    Standard,
+
+    /// Each byte is substituted through the key table, then the entry key is subtracted from it
+    ///
+    /// The entry key doesn't depend on the header keys or the file size, and the encrypted region
+    /// doesn't grow with the file name length.
+    Lamento,
+
+    /// Like [`DecryptMode::Standard`], but each byte is substituted three times and inverted
+    Totono,
+}
+
+impl DecryptMode {
+    /// Every known game whose data is encrypted with this scheme
+    pub fn games(self) -> Vec<Game> {
+        Game::iter().filter(|game| game.scheme() == self).collect()
+    }
+}
+
+/// Parameters needed to decrypt the archives of a specific title
+pub trait GameKeys {
+    /// Substitution table applied to each encrypted byte
+    fn encryption_key(&self) -> [u8; 256];
+
+    fn decrypt_mode(&self) -> DecryptMode;
+
+    /// Initial value of the entry key, before the bytes of the file name are subtracted from it
+    fn data_key_seed(&self) -> u32;
+
+    /// Whether file names are decrypted using the sum of the header keys instead of their product
+    ///
+    /// This picks the branch taken in [`decrypt_header`], [`detect_header_key_mode`] can work it
+    /// out from the archive alone.
+    fn add_bytes_if_encrypted(&self) -> bool;
+
+    /// Decrypts the data of entries, the [`SchemeDecryptor`] for [`GameKeys::decrypt_mode`] and
+    /// [`GameKeys::encryption_key`] unless overridden
+    fn decryptor(&self) -> Box<dyn Decryptor + '_> {
+        Box::new(SchemeDecryptor {
+            mode: self.decrypt_mode(),
+            table: self.encryption_key(),
+        })
+    }
+}
+
+impl GameKeys for Game {
+    fn encryption_key(&self) -> [u8; 256] {
+        Game::encryption_key(*self)
+    }
+
+    fn decrypt_mode(&self) -> DecryptMode {
+        self.scheme()
+    }
+
+    fn data_key_seed(&self) -> u32 {
+        match self {
+            Game::Axanael
+            | Game::Kikokugai
+            | Game::SonicomiTrialTwo
+            | Game::Sonicomi
+            | Game::LostX
+            | Game::DramaticalMurder
+            | Game::DramaticalMurderReConnect
+            | Game::MuramasaSS => 0x20101118,
+            Game::Totono => 0x12345678,
+            _ => 0x87654321,
+        }
+    }
+
+    fn add_bytes_if_encrypted(&self) -> bool {
+        matches!(self, Game::Lamento | Game::LamentoTrail)
+    }
+}
+
+/// A title that isn't part of [`Game`], described by its decryption parameters
+///
+/// This allows reading archives of games the crate doesn't know about, for example with
+/// parameters loaded from a config file. [`CustomGame::from`] copies the parameters of a known
+/// game as a starting point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomGame {
+    /// Substitution table applied to each encrypted byte
+    pub key: [u8; 256],
+
+    pub decrypt_mode: DecryptMode,
+
+    /// Whether file names are decrypted using the sum of the header keys instead of their product
+    pub add_bytes: bool,
+
+    /// Initial value of the entry key, see [`GameKeys::data_key_seed`]
+    pub seed: u32,
+}
+
+impl From<Game> for CustomGame {
+    fn from(game: Game) -> Self {
+        CustomGame {
+            key: game.encryption_key(),
+            decrypt_mode: game.decrypt_mode(),
+            add_bytes: game.add_bytes_if_encrypted(),
+            seed: game.data_key_seed(),
+        }
+    }
+}
+
+impl GameKeys for CustomGame {
+    fn encryption_key(&self) -> [u8; 256] {
+        self.key
+    }
+
+    fn decrypt_mode(&self) -> DecryptMode {
+        self.decrypt_mode
+    }
+
+    fn data_key_seed(&self) -> u32 {
+        self.seed
+    }
+
+    fn add_bytes_if_encrypted(&self) -> bool {
+        self.add_bytes
+    }
+}
+
+/// Reads `game` with `decryptor` in place of its own, for trying out a new scheme on archives of
+/// a known game without defining a whole [`GameKeys`] implementation
+///
+/// Everything that isn't about transforming the data, like the entry key, still comes from
+/// `game`.
+pub struct WithDecryptor<'a, G: ?Sized> {
+    pub game: &'a G,
+    pub decryptor: &'a dyn Decryptor,
+}
+
+impl<G: GameKeys + ?Sized> GameKeys for WithDecryptor<'_, G> {
+    fn encryption_key(&self) -> [u8; 256] {
+        self.game.encryption_key()
+    }
+
+    fn decrypt_mode(&self) -> DecryptMode {
+        self.game.decrypt_mode()
+    }
+
+    fn data_key_seed(&self) -> u32 {
+        self.game.data_key_seed()
+    }
+
+    fn add_bytes_if_encrypted(&self) -> bool {
+        self.game.add_bytes_if_encrypted()
+    }
+
+    fn decryptor(&self) -> Box<dyn Decryptor + '_> {
+        Box::new(self.decryptor)
+    }
+}
+
+impl<G: GameKeys + ?Sized> GameKeys for &G {
+    fn encryption_key(&self) -> [u8; 256] {
+        (**self).encryption_key()
+    }
+
+    fn decrypt_mode(&self) -> DecryptMode {
+        (**self).decrypt_mode()
+    }
+
+    fn data_key_seed(&self) -> u32 {
+        (**self).data_key_seed()
+    }
+
+    fn add_bytes_if_encrypted(&self) -> bool {
+        (**self).add_bytes_if_encrypted()
+    }
+
+    fn decryptor(&self) -> Box<dyn Decryptor + '_> {
+        (**self).decryptor()
+    }
+}
+
+/// Encoding used to decode file names and the data of text entries
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TextEncoding {
+    /// Guess the encoding of each name and file separately
+    #[default]
+    Detect,
+
+    /// Always decode with this encoding, e.g. for games that ship EUC-JP or UTF-16 scripts
+    Forced(&'static encoding_rs::Encoding),
+}
+
+impl TextEncoding {
+    /// Looks up an encoding by one of its WHATWG labels, such as `shift_jis`, `euc-jp` or
+    /// `utf-16le`
+    pub fn for_label(label: &str) -> Option<Self> {
+        encoding_rs::Encoding::for_label(label.as_bytes()).map(TextEncoding::Forced)
+    }
+
+    /// Decodes `bytes` to UTF-8, returning the text, the encoding that was used and whether
+    /// malformed bytes had to be replaced
+    pub fn decode(self, bytes: &[u8]) -> (String, &'static encoding_rs::Encoding, bool) {
+        let result = util::decode_text(bytes, self);
+        let (encoding, had_errors) = (result.encoding(), result.had_errors());
+
+        (result.into_text(), encoding, had_errors)
+    }
+}
+
+/// What happens to the data of entries whose extension [`infer`] doesn't know or that have none,
+/// which are usually, but not always, text
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextMode {
+    /// Keep the data exactly as it's stored in the archive
+    Raw,
+
+    /// Decode the data as text and convert it to UTF-8, which can corrupt binary files that merely
+    /// have an unknown extension. With [`TextEncoding::Detect`], data that is already UTF-8 or
+    /// contains control characters besides whitespace is kept as it is
+    Decode(TextEncoding),
+}
+
+impl Default for TextMode {
+    fn default() -> Self {
+        TextMode::Decode(TextEncoding::Detect)
+    }
+}
+
+/// What happens when a compressed entry decompresses to a different size than the entry table
+/// says, which usually means the wrong game was picked
+///
+/// [`ExtractOptions`] and [`NpaArchive`] can be set to either, the free functions reading data are
+/// always lenient and return the number of bytes written for callers to check themselves.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum SizePolicy {
+    /// Log a warning and keep the data, cut off at the expected size if it's longer
+    #[default]
+    Lenient,
+
+    /// Fail with [`NpaError::SizeMismatch`]
+    Strict,
 }
 
 /// Represents the header of an NPA (Nippon Ichi Archive) file
 /// Contains metadata about the archive structure
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct NpaHead {
     /// Magic number identifying the file format (7 bytes)
     pub head: [u8; 7],
@@ -105,16 +483,32 @@ pub struct NpaHead {
     /// Total number of entries (files + directories)
     pub total_count: u32,
 
-    /// Offset to the start of the file entries
+    /// Size of the entry table, which directly follows the fixed-size header and is followed by
+    /// the entries' data
     pub start: u32,
 }
 
+/// Size of the fixed part of the header, up to and including [`NpaHead::start`]
+///
+/// The layout is the 7-byte magic, `key_1` and `key_2`, the `compressed` and `encrypted` flags,
+/// `total_count`, `folder_count` and `file_count`, 8 unused bytes and `start`.
+pub const NPA_HEADER_SIZE: u64 = 0x29;
+
+impl NpaHead {
+    /// Absolute position where the data section begins, entry offsets are relative to it
+    pub fn data_start(&self) -> u64 {
+        self.start as u64 + NPA_HEADER_SIZE
+    }
+}
+
 #[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct NpaEntry {
     /// Length of the file name in bytes
     pub name_length: u32,
 
     /// Type indicator (1 = directory, 0 = file)
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
     pub type_: u8,
 
     /// Unique identifier for the file entry
@@ -130,22 +524,466 @@ pub struct NpaEntry {
     pub original_size: u32,
 
     /// Raw byte representation of the file path (before decoding to UTF-8)
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub un_decoded_file_path: Vec<u8>,
 
     /// Decoded UTF-8 file path as a PathBuf
     pub file_path: PathBuf,
+
+    /// Whether the name contained bytes that aren't valid in the encoding it was decoded with,
+    /// which were replaced in `file_path`, usually because the wrong game was picked
+    pub decode_lossy: bool,
 }
 
 impl NpaEntry {
     pub fn is_directory(&self) -> bool {
         self.type_ == 1
     }
+
+    /// Lowercased extension of the entry's file name, `None` if it has none
+    pub fn extension(&self) -> Option<String> {
+        self.file_path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+    }
+
+    /// Last component of the entry's path, `None` for an empty path
+    pub fn file_name(&self) -> Option<&OsStr> {
+        self.file_path.file_name()
+    }
+
+    /// Absolute position of the entry's data in the archive
+    ///
+    /// Entries read through [`entries`] are already checked not to point into the entry table.
+    pub fn data_offset(&self, header: &NpaHead) -> u64 {
+        header.data_start() + self.offset as u64
+    }
+
+    /// Number of bytes the entry takes up in the entry table
+    fn table_len(&self) -> u64 {
+        // Name length, name, type, file ID, offset, compressed and original size
+        4 + self.un_decoded_file_path.len() as u64 + 1 + 4 * 4
+    }
+
+    /// Checks that the entry's data lies within an archive of `archive_len` bytes and that its
+    /// sizes are plausible, so that corrupt entries fail before anything is allocated for them
+    pub fn validate(&self, header: &NpaHead, archive_len: u64) -> Result<(), NpaError> {
+        let offset = self.data_offset(header);
+        let available = archive_len.saturating_sub(offset);
+
+        if self.compressed_size as u64 > available {
+            return Err(NpaError::SizeMismatch {
+                expected: self.compressed_size as u64,
+                got: available,
+            });
+        }
+
+        if header.compressed
+            && self.original_size as u64 > (self.compressed_size as u64 + 1) * MAX_ZLIB_RATIO
+        {
+            return Err(NpaError::ImplausibleSize {
+                compressed: self.compressed_size,
+                original: self.original_size,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Upper bound of how much zlib's deflate can expand data when decompressing
+const MAX_ZLIB_RATIO: u64 = 1032;
+
+/// Sum of the original sizes of the files in `entries`, leaving out directories
+///
+/// Archives that aren't compressed don't always fill in the original size, their files are as
+/// large as their stored size.
+pub fn total_original_size<'a>(entries: impl IntoIterator<Item = &'a NpaEntry>) -> u64 {
+    entries
+        .into_iter()
+        .filter(|entry| !entry.is_directory())
+        .map(|entry| entry.original_size as u64)
+        .sum()
+}
+
+/// Number of files in `entries`, which unlike [`NpaHead::file_count`] comes from the entry table
+/// itself
+pub fn file_count_actual<'a>(entries: impl IntoIterator<Item = &'a NpaEntry>) -> usize {
+    entries
+        .into_iter()
+        .filter(|entry| !entry.is_directory())
+        .count()
+}
+
+/// Summary of an archive's metadata, gathered from the header and entry table
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ArchiveInfo {
+    /// The parsed archive header
+    pub header: NpaHead,
+
+    /// Game whose keys are used to read the archive, `None` if it wasn't picked or detected
+    pub game: Option<Game>,
+
+    /// Number of file entries in the entry table
+    pub file_count: usize,
+
+    /// Number of directory entries in the entry table
+    pub folder_count: usize,
+
+    /// Sum of the stored (possibly compressed) sizes of all files
+    pub compressed_size: u64,
+
+    /// Sum of the original sizes of all files
+    pub original_size: u64,
+
+    /// Inconsistencies noticed between the header and the entry table
+    pub warnings: Vec<String>,
+}
+
+impl ArchiveInfo {
+    pub fn new(header: &NpaHead, entries: &[NpaEntry], game: Option<Game>) -> Self {
+        let (folders, files): (Vec<&NpaEntry>, Vec<&NpaEntry>) =
+            entries.iter().partition(|entry| entry.is_directory());
+
+        let mut warnings = Vec::new();
+
+        if header.total_count as usize != entries.len() {
+            warnings.push(format!(
+                "Header claims {} entries, but {} were read",
+                header.total_count,
+                entries.len()
+            ));
+        }
+
+        let file_count = file_count_actual(entries);
+
+        if header.file_count as usize != file_count {
+            warnings.push(format!(
+                "Header claims {} files, but the entry table has {}",
+                header.file_count, file_count
+            ));
+        }
+
+        if header.folder_count as usize != folders.len() {
+            warnings.push(format!(
+                "Header claims {} directories, but the entry table has {}",
+                header.folder_count,
+                folders.len()
+            ));
+        }
+
+        ArchiveInfo {
+            header: header.clone(),
+            game,
+            file_count,
+            folder_count: folders.len(),
+            compressed_size: files.iter().map(|e| e.compressed_size as u64).sum(),
+            original_size: total_original_size(files),
+            warnings,
+        }
+    }
+}
+
+/// An opened archive, owning its reader together with the parsed header and entry table
+#[derive(Debug)]
+pub struct NpaArchive<R> {
+    reader: R,
+    header: NpaHead,
+    entries: Vec<NpaEntry>,
+    game: Option<Game>,
+
+    /// Length of the whole archive, measured once when it's opened so that reading an entry can
+    /// check its bounds without seeking to the end. `None` if that failed, it's then measured
+    /// again for every entry so that the error is reported.
+    archive_len: Option<u64>,
+    text_mode: TextMode,
+    size_policy: SizePolicy,
+}
+
+impl<R: Read + Seek> NpaArchive<R> {
+    /// Parses the header and entry table of the archive, `game` is needed to decrypt the entry names
+    pub fn open(reader: R, game: Game) -> Result<Self, NpaError> {
+        Self::open_with_encoding(reader, game, TextEncoding::Detect)
+    }
+
+    /// Same as [`NpaArchive::open`], but decodes names and text entries with `encoding`
+    pub fn open_with_encoding(
+        mut reader: R,
+        game: Game,
+        encoding: TextEncoding,
+    ) -> Result<Self, NpaError> {
+        let header = parse_head(&mut reader)?;
+        let entries = read_entries_with_progress(
+            &mut reader,
+            &header,
+            game.add_bytes_if_encrypted(),
+            encoding,
+            |_| {},
+        )?;
+
+        Ok(Self::from_parts(reader, header, entries)
+            .with_game(game)
+            .with_encoding(encoding))
+    }
+
+    /// Wraps a header and entry table that were already read from `reader`, for callers that
+    /// parse them themselves, e.g. to report progress through [`read_entries_with_progress`]
+    ///
+    ///
+    /// The archive's length is measured here, so it isn't looked up again for every entry that's
+    /// read.
+    pub fn from_parts(mut reader: R, header: NpaHead, entries: Vec<NpaEntry>) -> Self {
+        let archive_len = reader.seek(SeekFrom::End(0)).ok();
+
+        NpaArchive {
+            reader,
+            header,
+            entries,
+            game: None,
+            archive_len,
+            text_mode: TextMode::default(),
+            size_policy: SizePolicy::default(),
+        }
+    }
+
+    /// Records which game the archive is from, for [`NpaArchive::info`]
+    pub fn with_game(mut self, game: Game) -> Self {
+        self.game = Some(game);
+        self
+    }
+
+    /// Sets the encoding used to decode text entries when reading them
+    pub fn with_encoding(self, encoding: TextEncoding) -> Self {
+        self.with_text_mode(TextMode::Decode(encoding))
+    }
+
+    /// Sets whether text entries are decoded or kept as they are when reading them
+    pub fn with_text_mode(mut self, text_mode: TextMode) -> Self {
+        self.text_mode = text_mode;
+        self
+    }
+
+    /// Changes how text entries are read from now on, see [`NpaArchive::with_text_mode`]
+    pub fn set_text_mode(&mut self, text_mode: TextMode) {
+        self.text_mode = text_mode;
+    }
+
+    /// Sets whether reading an entry fails if it doesn't decompress to its expected size
+    pub fn with_size_policy(mut self, size_policy: SizePolicy) -> Self {
+        self.size_policy = size_policy;
+        self
+    }
+
+    pub fn header(&self) -> &NpaHead {
+        &self.header
+    }
+
+    pub fn entries(&self) -> &[NpaEntry] {
+        &self.entries
+    }
+
+    /// Game the archive was opened with, or that was recorded with [`NpaArchive::with_game`]
+    pub fn game(&self) -> Option<Game> {
+        self.game
+    }
+
+    /// Length of the whole archive in bytes, `None` if it couldn't be measured
+    pub fn archive_len(&self) -> Option<u64> {
+        self.archive_len
+    }
+
+    /// Summary of the header, the entry table and the archive's game
+    pub fn info(&self) -> ArchiveInfo {
+        ArchiveInfo::new(&self.header, &self.entries, self.game)
+    }
+
+    /// Reads the data of `entry`, see [`read_entry_data`]
+    pub fn read(&mut self, entry: &NpaEntry, game: Game) -> Result<Vec<u8>, NpaError> {
+        let mut buffer = Vec::new();
+        self.read_to(entry, game, &mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    /// Reads the data of `entry` exactly as it's stored, whatever the archive's [`TextMode`]
+    pub fn read_raw(&mut self, entry: &NpaEntry, game: Game) -> Result<Vec<u8>, NpaError> {
+        let mut buffer = Vec::new();
+        let archive_len = self.measured_len()?;
+        write_entry_data(
+            &mut self.reader,
+            &self.header,
+            entry,
+            &game,
+            TextMode::Raw,
+            self.size_policy,
+            archive_len,
+            &mut buffer,
+        )?;
+
+        Ok(buffer)
+    }
+
+    /// Reads the data of the entry at `path`, see [`find_entry`] for how paths are matched
+    pub fn read_path(&mut self, path: &Path, game: Game) -> Result<Vec<u8>, NpaError> {
+        let entry = find_entry(&self.entries, path)
+            .ok_or_else(|| NpaError::EntryNotFound(path.to_path_buf()))?
+            .clone();
+
+        self.read(&entry, game)
+    }
+
+    /// Writes the data of `entry` to `out`, see [`read_entry_data_to`]
+    pub fn read_to<W: Write>(
+        &mut self,
+        entry: &NpaEntry,
+        game: Game,
+        out: &mut W,
+    ) -> Result<u64, NpaError> {
+        let archive_len = self.measured_len()?;
+        write_entry_data(
+            &mut self.reader,
+            &self.header,
+            entry,
+            &game,
+            self.text_mode,
+            self.size_policy,
+            archive_len,
+            out,
+        )
+        .map(|(written, _)| written)
+    }
+
+    /// Writes `entry` to `relative` inside `output_directory`, see [`extract_entry`]
+    pub fn extract_entry(
+        &mut self,
+        entry: &NpaEntry,
+        output_directory: &Path,
+        relative: &Path,
+        game: Game,
+        options: &ExtractOptions,
+    ) -> Result<ExtractReport, NpaError> {
+        let archive_len = self
+            .measured_len()
+            .map_err(|err| err.with_path(&entry.file_path))?;
+        let path = extract::output_path(entry, output_directory, relative)?;
+
+        extract::extract_to(
+            &mut self.reader,
+            &self.header,
+            entry,
+            &path,
+            game,
+            options,
+            archive_len,
+        )
+    }
+
+    /// Extracts the entries [`select_entries`] picks into `output_directory`, see [`extract_all`]
+    pub fn extract_all(
+        &mut self,
+        output_directory: &Path,
+        game: Game,
+        options: &ExtractOptions,
+    ) -> Result<ExtractReport, NpaError> {
+        extract_all(
+            &mut self.reader,
+            &self.header,
+            &self.entries,
+            output_directory,
+            game,
+            options,
+        )
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// [`NpaArchive::archive_len`], measuring it again if that failed when the archive was opened
+    fn measured_len(&mut self) -> Result<u64, NpaError> {
+        match self.archive_len {
+            Some(len) => Ok(len),
+            None => Ok(self.reader.seek(SeekFrom::End(0))?),
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl NpaArchive<std::io::Cursor<memmap2::Mmap>> {
+    /// Maps the archive at `path` into memory instead of reading it through a file handle
+    ///
+    /// Reading an entry is then a copy out of the mapping rather than a seek and a read, and
+    /// [`NpaArchive::for_each_entry_shared`] lets parallel workers share the one mapping instead of
+    /// each opening the file.
+    pub fn open_mmap(path: &Path, game: Game) -> Result<Self, NpaError> {
+        let file = std::fs::File::open(path)?;
+
+        // SAFETY: Changing the file while it's mapped is undefined behavior, like for any mapping.
+        // Archives are game assets that nothing writes to while they are being read.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+
+        Self::open(std::io::Cursor::new(map), game)
+    }
+
+    /// Calls `f` for every file on rayon's thread pool with the entry's index and a reader over
+    /// the mapped archive, directories are skipped
+    ///
+    /// Like [`for_each_entry_parallel`], but without a file handle per worker thread. The first
+    /// error stops the remaining entries from being processed.
+    pub fn for_each_entry_shared<F>(&self, f: F) -> Result<(), NpaError>
+    where
+        F: Fn(usize, &NpaEntry, &mut std::io::Cursor<&[u8]>) -> Result<(), NpaError> + Sync,
+    {
+        use rayon::prelude::*;
+
+        let bytes: &[u8] = self.reader.get_ref();
+
+        self.entries
+            .par_iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.is_directory())
+            .try_for_each_init(
+                || std::io::Cursor::new(bytes),
+                |reader, (index, entry)| f(index, entry, reader),
+            )
+    }
+}
+
+/// Finds the entry whose decoded path is `path`
+///
+/// Components are compared case-insensitively since the archives come from Windows, and both `/`
+/// and `\` are accepted as separators, so `scripts/main.scr` and `scripts\Main.scr` find the
+/// same entry.
+pub fn find_entry<'a>(entries: &'a [NpaEntry], path: &Path) -> Option<&'a NpaEntry> {
+    let wanted = path.to_string_lossy();
+    let wanted: Vec<String> = wanted
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+
+    entries.iter().find(|entry| {
+        entry.file_path.components().count() == wanted.len()
+            && entry
+                .file_path
+                .components()
+                .zip(&wanted)
+                .all(|(component, wanted)| {
+                    component.as_os_str().to_string_lossy().to_lowercase() == *wanted
+                })
+    })
 }
 
-pub fn parse_head<R: Read>(reader: &mut R) -> Result<NpaHead, std::io::Error> {
+pub fn parse_head<R: Read>(reader: &mut R) -> Result<NpaHead, NpaError> {
     let mut magic = [0u8; 7];
     reader.read_exact(&mut magic)?;
 
+    // Every known game writes `NPA\x01`, the remaining bytes are usually zero but not checked
+    if !magic.starts_with(b"NPA\x01") {
+        return Err(NpaError::BadMagic(magic));
+    }
+
     let key_1 = util::read_u32_le(reader)?;
     let key_2 = util::read_u32_le(reader)?;
     let compressed = util::read_u8(reader)? == 1;
@@ -178,161 +1016,781 @@ pub fn read_entries<R: Read>(
     reader: &mut R,
     header: &NpaHead,
     add_bytes_if_encrypted: bool,
-) -> Result<Vec<NpaEntry>, std::io::Error> {
-    let mut entries = Vec::with_capacity(header.total_count as usize);
+) -> Result<Vec<NpaEntry>, NpaError> {
+    read_entries_with_progress(
+        reader,
+        header,
+        add_bytes_if_encrypted,
+        TextEncoding::Detect,
+        |_| {},
+    )
+}
 
-    for i in 0..header.total_count as usize {
-        let entry = read_entry(reader, i, header, add_bytes_if_encrypted)?;
+/// Most entries [`read_entries`] allocates room for up front, larger tables grow as they're read
+const MAX_PREALLOCATED_ENTRIES: usize = 65536;
 
-        entries.push(entry);
+/// Same as [`read_entries`], but decodes names with `encoding` and calls `on_entry` with the number
+/// of entries read so far after each one, so that parsing huge entry tables can report progress
+pub fn read_entries_with_progress<R: Read, F: FnMut(usize)>(
+    reader: &mut R,
+    header: &NpaHead,
+    add_bytes_if_encrypted: bool,
+    encoding: TextEncoding,
+    mut on_entry: F,
+) -> Result<Vec<NpaEntry>, NpaError> {
+    // The count comes straight from the header, so a corrupt one mustn't decide the allocation
+    let mut entries =
+        Vec::with_capacity((header.total_count as usize).min(MAX_PREALLOCATED_ENTRIES));
+
+    for entry in self::entries(reader, header, add_bytes_if_encrypted).with_encoding(encoding) {
+        entries.push(entry?);
+        on_entry(entries.len());
     }
 
     Ok(entries)
 }
 
-pub fn read_entry<R: Read>(
-    reader: &mut R,
-    index: usize,
-    header: &NpaHead,
+/// Reads the entry table one entry at a time, the lazy version of [`read_entries`]
+///
+/// The reader has to be at the start of the table, right after the header. This allows working
+/// with entries while the rest of the table is still being read, without keeping all of them in
+/// memory.
+pub fn entries<'a, R: Read>(
+    reader: &'a mut R,
+    header: &'a NpaHead,
     add_bytes_if_encrypted: bool,
-) -> Result<NpaEntry, std::io::Error> {
-    let nlength = read_u32_le(reader)? as usize;
+) -> EntryIter<'a, R> {
+    EntryIter {
+        reader,
+        header,
+        add_bytes_if_encrypted,
+        encoding: TextEncoding::Detect,
+        index: 0,
+        table_end: NPA_HEADER_SIZE,
+        name_buffer: Vec::new(),
+        failed: false,
+    }
+}
 
-    let mut file_name = vec![0u8; nlength];
-    reader.read_exact(&mut file_name)?;
+/// Iterator over the entry table returned by [`entries`]
+///
+/// It stops after the first error, a table that ends early yields
+/// [`NpaError::TruncatedEntryTable`]. An entry whose data overlaps the part of the table read so
+/// far, which happens when the header's `start` is smaller than the table really is, yields
+/// [`NpaError::DataOverlapsTable`].
+#[derive(Debug)]
+pub struct EntryIter<'a, R> {
+    reader: &'a mut R,
+    header: &'a NpaHead,
+    add_bytes_if_encrypted: bool,
+    encoding: TextEncoding,
+    index: usize,
 
-    for (x, byte) in file_name.iter_mut().enumerate() {
-        *byte = byte.wrapping_add(decrypt_header(
-            x as u32,
-            index as u32,
-            header,
-            add_bytes_if_encrypted,
-        ));
-    }
+    /// Absolute position right after the last entry read
+    table_end: u64,
 
-    let decoded_path = util::decode_text(&file_name);
+    /// Reused for the name of every entry instead of allocating a new one each time
+    name_buffer: Vec<u8>,
+    failed: bool,
+}
 
-    if decoded_path.had_errors() {
-        log::warn!("Failed to cleanly decode path: {}", decoded_path.text());
+impl<R> EntryIter<'_, R> {
+    /// Decodes names with `encoding` instead of detecting it for each one
+    pub fn with_encoding(mut self, encoding: TextEncoding) -> Self {
+        self.encoding = encoding;
+        self
     }
+}
 
-    let file_path = decoded_path
-        .text()
-        .split('\\')
-        .filter(|s| !s.is_empty())
-        .fold(PathBuf::new(), |p, c| p.join(c));
+impl<R: Read> Iterator for EntryIter<'_, R> {
+    type Item = Result<NpaEntry, NpaError>;
 
-    let type_ = read_u8(reader)?;
-    let file_id = read_u32_le(reader)?;
-    let offset = read_u32_le(reader)?;
-    let compressed_size = read_u32_le(reader)?;
-    let original_size = read_u32_le(reader)?;
-    let un_decoded_file_path = file_name;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.index >= self.header.total_count as usize {
+            return None;
+        }
 
-    Ok(NpaEntry {
-        name_length: nlength as u32,
-        type_,
-        file_id,
-        offset,
-        compressed_size,
-        original_size,
-        un_decoded_file_path,
-        file_path,
-    })
-}
+        let result = match read_entry_with_buffer(
+            self.reader,
+            self.index,
+            self.header,
+            self.add_bytes_if_encrypted,
+            self.encoding,
+            &mut self.name_buffer,
+        ) {
+            Err(NpaError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Err(NpaError::TruncatedEntryTable {
+                    expected: self.header.total_count,
+                    read: self.index,
+                })
+            }
+            result => result,
+        };
 
-pub fn read_entry_data<R: Read + Seek>(
+        let result = result.and_then(|entry| {
+            self.table_end += entry.table_len();
+            let offset = entry.data_offset(self.header);
+
+            if entry.compressed_size > 0 && offset < self.table_end {
+                return Err(NpaError::DataOverlapsTable {
+                    offset,
+                    table_end: self.table_end,
+                }
+                .with_path(&entry.file_path));
+            }
+
+            Ok(entry)
+        });
+
+        self.failed = result.is_err();
+        self.index += 1;
+
+        Some(result)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.failed {
+            true => (0, Some(0)),
+            false => (0, Some(self.header.total_count as usize - self.index)),
+        }
+    }
+}
+
+pub fn read_entry<R: Read>(
     reader: &mut R,
+    index: usize,
     header: &NpaHead,
-    entry: &NpaEntry,
-    game: Game,
-) -> Result<Vec<u8>, std::io::Error> {
-    log::debug!("Reading \"{}\"", entry.file_path.display());
-    reader.seek(SeekFrom::Start((entry.offset + header.start + 0x29) as u64))?;
+    add_bytes_if_encrypted: bool,
+) -> Result<NpaEntry, NpaError> {
+    read_entry_with_encoding(
+        reader,
+        index,
+        header,
+        add_bytes_if_encrypted,
+        TextEncoding::Detect,
+    )
+}
 
-    let mut buffer = vec![0u8; entry.compressed_size as usize];
-    reader.read_exact(&mut buffer)?;
+/// Same as [`read_entry`], but decodes the name with `encoding`
+pub fn read_entry_with_encoding<R: Read>(
+    reader: &mut R,
+    index: usize,
+    header: &NpaHead,
+    add_bytes_if_encrypted: bool,
+    encoding: TextEncoding,
+) -> Result<NpaEntry, NpaError> {
+    read_entry_with_buffer(
+        reader,
+        index,
+        header,
+        add_bytes_if_encrypted,
+        encoding,
+        &mut Vec::new(),
+    )
+}
 
-    if header.encrypted {
-        let key = decrypt_data(entry, header, game);
-        let mut len = 0x1000;
+/// Same as [`read_entry`], but reads the file name into `name_buffer` so that it can be reused
+/// across entries instead of allocating a new one each time.
+fn read_entry_with_buffer<R: Read>(
+    reader: &mut R,
+    index: usize,
+    header: &NpaHead,
+    add_bytes_if_encrypted: bool,
+    encoding: TextEncoding,
+    name_buffer: &mut Vec<u8>,
+) -> Result<NpaEntry, NpaError> {
+    let nlength = read_u32_le(reader)? as usize;
 
-        if game != Game::Lamento && game != Game::LamentoTrail {
-            len += entry.un_decoded_file_path.len() as u32;
-        }
+    name_buffer.clear();
+    reader.take(nlength as u64).read_to_end(name_buffer)?;
 
-        for x in 0..entry.compressed_size.min(len) {
-            buffer[x as usize] = match game {
-                Game::Lamento | Game::LamentoTrail => {
-                    game.encryption_key()[buffer[x as usize] as usize].wrapping_sub(key)
-                }
+    if name_buffer.len() != nlength {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
 
-                Game::Totono => {
-                    let mut r = buffer[x as usize];
-                    r = game.encryption_key()[r as usize];
-                    r = game.encryption_key()[r as usize];
-                    r = game.encryption_key()[r as usize];
-                    r = !r;
+    let file_name = name_buffer;
 
-                    r.wrapping_sub(key).wrapping_sub(x as u8)
-                }
+    for (x, byte) in file_name.iter_mut().enumerate() {
+        *byte = byte.wrapping_add(decrypt_header(
+            x as u32,
+            index as u32,
+            header,
+            add_bytes_if_encrypted,
+        ));
+    }
+
+    let decoded_path = util::decode_text(file_name, encoding);
+    let decode_lossy = decoded_path.had_errors();
+
+    if decode_lossy {
+        log::warn!(
+            "Failed to cleanly decode path as {}: {}",
+            decoded_path.encoding_description(),
+            decoded_path.text()
+        );
+    }
+
+    debug!(
+        "Decoded path \"{}\" as {}",
+        decoded_path.text(),
+        decoded_path.encoding().name()
+    );
+
+    let file_path = decoded_path
+        .text()
+        .split(['\\', '/'])
+        .filter(|s| !s.is_empty())
+        .fold(PathBuf::new(), |p, c| p.join(c));
 
-                _ => game.encryption_key()[buffer[x as usize] as usize]
-                    .wrapping_sub(key)
-                    .wrapping_sub(x as u8),
+    let type_ = read_u8(reader)?;
+    let file_id = read_u32_le(reader)?;
+    let offset = read_u32_le(reader)?;
+    let compressed_size = read_u32_le(reader)?;
+    let original_size = read_u32_le(reader)?;
+    // The buffer is handed back to the caller for the next entry, so the name has to be copied out
+    let un_decoded_file_path = file_name.to_vec();
+
+    Ok(NpaEntry {
+        name_length: nlength as u32,
+        type_,
+        file_id,
+        offset,
+        compressed_size,
+        original_size,
+        un_decoded_file_path,
+        decode_lossy,
+        file_path,
+    })
+}
+
+/// How many names [`detect_header_key_mode`] decrypts with each key mode
+const KEY_MODE_SAMPLES: usize = 8;
+
+/// Works out the `add_bytes_if_encrypted` flag for the archive in `reader` without knowing its game
+///
+/// The flag picks the branch in [`decrypt_header`] that derives the name key from the sum of the
+/// header keys instead of their product. The first few names are decrypted both ways, and the way
+/// that gives more names which look like text with a file extension (or directories) wins, ties
+/// going to the product, which most games use. Unencrypted archives always use the product.
+///
+/// The reader is left at the start of the entry table, so the entries can be read right after.
+pub fn detect_header_key_mode<R: Read + Seek>(
+    reader: &mut R,
+    header: &NpaHead,
+) -> Result<bool, NpaError> {
+    if !header.encrypted {
+        return Ok(false);
+    }
+
+    reader.seek(SeekFrom::Start(NPA_HEADER_SIZE))?;
+
+    let mut scores = [0usize; 2];
+
+    for index in 0..(header.total_count as usize).min(KEY_MODE_SAMPLES) {
+        let length = read_u32_le(reader)?;
+        let mut name = Vec::new();
+        reader.take(length as u64).read_to_end(&mut name)?;
+
+        let type_ = read_u8(reader)?;
+        // file_id, offset, compressed_size and original_size
+        reader.seek(SeekFrom::Current(16))?;
+
+        for (add_bytes, score) in [false, true].into_iter().zip(&mut scores) {
+            let decrypted: Vec<u8> = name
+                .iter()
+                .enumerate()
+                .map(|(x, byte)| {
+                    byte.wrapping_add(decrypt_header(x as u32, index as u32, header, add_bytes))
+                })
+                .collect();
+
+            if looks_like_entry_name(&decrypted, type_ == 1) {
+                *score += 1;
             }
         }
     }
 
+    reader.seek(SeekFrom::Start(NPA_HEADER_SIZE))?;
+
+    let add_bytes = scores[1] > scores[0];
+    debug!("Detected header key mode, add bytes: {add_bytes} (scores {scores:?})");
+
+    Ok(add_bytes)
+}
+
+/// Same as [`read_entries`], but works out `add_bytes_if_encrypted` with [`detect_header_key_mode`]
+pub fn read_entries_auto<R: Read + Seek>(
+    reader: &mut R,
+    header: &NpaHead,
+) -> Result<Vec<NpaEntry>, NpaError> {
+    let add_bytes_if_encrypted = detect_header_key_mode(reader, header)?;
+    read_entries(reader, header, add_bytes_if_encrypted)
+}
+
+fn looks_like_entry_name(name: &[u8], is_directory: bool) -> bool {
+    if name.is_empty() || !util::looks_like_text(name) {
+        return false;
+    }
+
+    if is_directory {
+        return true;
+    }
+
+    name.iter()
+        .rposition(|&b| b == b'.')
+        .map(|dot| &name[dot + 1..])
+        .is_some_and(|extension| {
+            (1..=4).contains(&extension.len())
+                && extension.iter().all(|b| b.is_ascii_alphanumeric())
+        })
+}
+
+pub fn read_entry_data<R: Read + Seek>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    game: Game,
+) -> Result<Vec<u8>, NpaError> {
+    read_entry_data_generic(reader, header, entry, &game, TextMode::default())
+}
+
+/// Same as [`read_entry_data`], but accepts any [`GameKeys`], such as a [`CustomGame`] defined at
+/// runtime, and handles text entries according to `text_mode`
+pub fn read_entry_data_generic<R: Read + Seek, G: GameKeys + ?Sized>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    game: &G,
+    text_mode: TextMode,
+) -> Result<Vec<u8>, NpaError> {
+    let mut buffer = Vec::new();
+    read_entry_data_to_generic(
+        reader,
+        header,
+        entry,
+        game,
+        text_mode,
+        SizePolicy::default(),
+        &mut buffer,
+    )?;
+
+    Ok(buffer)
+}
+
+/// Same as [`read_entry_data`], but decrypts with the substitution table `key` and the transform
+/// of `scheme` instead of the parameters of a known game, for trying out the table of a new title
+///
+/// The entry key starts from the seed of the first [`Game`] using `scheme`, and file names are
+/// expected to be decrypted the way those games do it. Use a [`CustomGame`] with
+/// [`read_entry_data_generic`] when those need to differ as well.
+pub fn read_entry_data_with_key<R: Read + Seek>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    key: &[u8; 256],
+    scheme: DecryptMode,
+) -> Result<Vec<u8>, NpaError> {
+    let game = CustomGame {
+        key: *key,
+        ..CustomGame::from(scheme.games()[0])
+    };
+
+    read_entry_data_generic(reader, header, entry, &game, TextMode::default())
+}
+
+/// Same as [`read_entry_data`], but writes the data to `out` instead of returning it and returns
+/// the number of bytes written
+///
+/// Entries whose extension [`infer`] knows, and all entries with [`TextMode::Raw`], are
+/// decompressed straight into `out`, so memory use stays flat no matter how large they are. Other
+/// entries may have to be decoded as text, which needs all of their data, so those are still
+/// buffered.
+pub fn read_entry_data_to<R: Read + Seek, W: Write>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    game: Game,
+    out: &mut W,
+) -> Result<u64, NpaError> {
+    read_entry_data_to_generic(
+        reader,
+        header,
+        entry,
+        &game,
+        TextMode::default(),
+        SizePolicy::default(),
+        out,
+    )
+}
+
+/// Same as [`read_entry_data_to`], but accepts any [`GameKeys`], handles text entries according
+/// to `text_mode` and data that doesn't decompress to the expected size according to
+/// `size_policy`
+///
+/// Like every free function reading an entry, this seeks to the end of `reader` to check the
+/// entry's bounds with [`NpaEntry::validate`]. [`NpaArchive`] measures the length only once.
+pub fn read_entry_data_to_generic<R: Read + Seek, G: GameKeys + ?Sized, W: Write>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    game: &G,
+    text_mode: TextMode,
+    size_policy: SizePolicy,
+    out: &mut W,
+) -> Result<u64, NpaError> {
+    let archive_len = reader.seek(SeekFrom::End(0))?;
+
+    write_entry_data(
+        reader,
+        header,
+        entry,
+        game,
+        text_mode,
+        size_policy,
+        archive_len,
+        out,
+    )
+    .map(|(written, _)| written)
+}
+
+/// Reads at most `len` bytes from the start of the data of `entry`, decrypting and decompressing
+/// only as much as needed, e.g. to check its signature with [`infer`]
+///
+/// Text entries aren't decoded.
+pub fn read_entry_prefix<R: Read + Seek, G: GameKeys + ?Sized>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    game: &G,
+    len: usize,
+) -> Result<Vec<u8>, NpaError> {
+    let archive_len = reader.seek(SeekFrom::End(0))?;
+    let stored = stored_data(reader, header, entry, game, archive_len)?;
+    let mut prefix = Vec::with_capacity(len);
+
     if header.compressed {
-        let mut z_buffer = Vec::with_capacity(entry.original_size as usize);
-        let mut decoder = ZlibDecoder::new(reader);
+        ZlibDecoder::new(stored)
+            .take(len as u64)
+            .read_to_end(&mut prefix)
+            .map_err(NpaError::DecodeFailed)?;
+    } else {
+        stored.take(len as u64).read_to_end(&mut prefix)?;
+    }
+
+    Ok(prefix)
+}
+
+/// [`read_entry_data_to_generic`] within an archive of `archive_len` bytes, also returning whether
+/// decoding the data as text hit malformed bytes
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_entry_data<R: Read + Seek, G: GameKeys + ?Sized, W: Write>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    game: &G,
+    text_mode: TextMode,
+    size_policy: SizePolicy,
+    archive_len: u64,
+    out: &mut W,
+) -> Result<(u64, bool), NpaError> {
+    let TextMode::Decode(encoding) = text_mode else {
+        return Ok((
+            write_decrypted_data(reader, header, entry, game, size_policy, archive_len, out)?,
+            false,
+        ));
+    };
+
+    // Entries without an extension are as unknown as ones whose extension infer doesn't know
+    if entry
+        .extension()
+        .is_some_and(|extension| infer::is_supported(&extension))
+    {
+        return Ok((
+            write_decrypted_data(reader, header, entry, game, size_policy, archive_len, out)?,
+            false,
+        ));
+    }
+
+    let mut buffer = read_decrypted_data(reader, header, entry, game, size_policy, archive_len)?;
+    let mut lossy = false;
 
-        debug!("Decompressing \"{}\"", entry.file_path.display());
+    // Data that is already valid UTF-8 would only get mangled by being decoded again, unless an
+    // encoding was explicitly requested: UTF-16 text without BOM can be valid UTF-8 as well. The
+    // same goes for binary data without an extension, which is common in archives stored flat
+    let keep = encoding == TextEncoding::Detect
+        && (std::str::from_utf8(&buffer).is_ok() || util::has_control_bytes(&buffer));
+
+    if !keep {
+        debug!("Decoding \"{}\"", entry.file_path.display());
 
-        decoder.read_to_end(&mut z_buffer)?;
+        let result = util::decode_text(&buffer, encoding);
+        lossy = result.had_errors();
 
-        if z_buffer.len() != entry.original_size as usize {
+        if lossy {
             log::warn!(
-                "Warning while decompressing \"{}\": decompressed size ({}) != expected size ({})",
+                "Failed to cleanly decode file as {}: {}",
+                result.encoding_description(),
+                result.text()
+            );
+        }
+
+        buffer = result.text().as_bytes().to_vec();
+    }
+
+    out.write_all(&buffer)?;
+
+    Ok((buffer.len() as u64, lossy))
+}
+
+/// Reads, decrypts and decompresses the data of `entry`, without decoding text
+fn read_decrypted_data<R: Read + Seek, G: GameKeys + ?Sized>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    game: &G,
+    size_policy: SizePolicy,
+    archive_len: u64,
+) -> Result<Vec<u8>, NpaError> {
+    let expected = if header.compressed {
+        entry.original_size
+    } else {
+        entry.compressed_size
+    };
+
+    let mut buffer = Vec::with_capacity(expected as usize);
+    write_decrypted_data(
+        reader,
+        header,
+        entry,
+        game,
+        size_policy,
+        archive_len,
+        &mut buffer,
+    )?;
+
+    Ok(buffer)
+}
+
+/// Streams the decrypted and decompressed data of `entry` into `out`, without decoding text
+fn write_decrypted_data<R: Read + Seek, G: GameKeys + ?Sized, W: Write>(
+    reader: &mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    game: &G,
+    size_policy: SizePolicy,
+    archive_len: u64,
+    out: &mut W,
+) -> Result<u64, NpaError> {
+    let mut stored = stored_data(reader, header, entry, game, archive_len)?;
+
+    if !header.compressed {
+        let written = std::io::copy(&mut stored, out)?;
+
+        if written != entry.compressed_size as u64 {
+            return Err(NpaError::SizeMismatch {
+                expected: entry.compressed_size as u64,
+                got: written,
+            });
+        }
+
+        return Ok(written);
+    }
+
+    debug!("Decompressing \"{}\"", entry.file_path.display());
+
+    let mut decoder = ZlibDecoder::new(stored);
+    let mut chunk = vec![0u8; 0x10000];
+    let mut written = 0u64;
+
+    loop {
+        let read = match decoder.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            // Depending on the version, flate2 reports a stream that ends early, which data
+            // decrypted with the wrong key tends to be, as an error or as the end of the data
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(NpaError::DecodeFailed(err)),
+        };
+
+        // Anything past the declared size doesn't belong to the file
+        let keep = (entry.original_size as u64 - written).min(read as u64);
+
+        out.write_all(&chunk[..keep as usize])?;
+        written += keep;
+
+        if keep < read as u64 {
+            if size_policy == SizePolicy::Strict {
+                let rest = std::io::copy(&mut decoder, &mut std::io::sink())
+                    .map_err(NpaError::DecodeFailed)?;
+
+                return Err(NpaError::SizeMismatch {
+                    expected: entry.original_size as u64,
+                    got: written + (read as u64 - keep) + rest,
+                });
+            }
+
+            log::warn!(
+                "Warning while decompressing \"{}\": data is longer than the expected size ({}), truncating it",
                 entry.file_path.display(),
-                z_buffer.len(),
                 entry.original_size
             );
+
+            return Ok(written);
+        }
+    }
+
+    // Longer data was already cut off above, so this was the end of the compressed stream
+    if written < entry.original_size as u64 {
+        if size_policy == SizePolicy::Strict {
+            return Err(NpaError::SizeMismatch {
+                expected: entry.original_size as u64,
+                got: written,
+            });
         }
 
-        buffer = z_buffer;
+        log::warn!(
+            "Warning while decompressing \"{}\": compressed data ends after {} of {} bytes, the wrong game was probably picked",
+            entry.file_path.display(),
+            written,
+            entry.original_size
+        );
     }
 
-    let extension = entry
-        .file_path
-        .extension()
-        .ok_or_else(|| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "Entry has no extension")
-        })?
-        .to_string_lossy()
-        .to_lowercase();
+    Ok(written)
+}
+
+/// Returns a reader over the bytes stored for `entry`, with the encrypted window at their start
+/// already decrypted, after checking that they lie within the archive's `archive_len` bytes
+fn stored_data<'a, R: Read + Seek, G: GameKeys + ?Sized>(
+    reader: &'a mut R,
+    header: &NpaHead,
+    entry: &NpaEntry,
+    game: &G,
+    archive_len: u64,
+) -> Result<impl Read + 'a, NpaError> {
+    log::debug!("Reading \"{}\"", entry.file_path.display());
 
-    let can_infer = infer::is_supported(extension.as_str());
+    entry.validate(header, archive_len)?;
 
-    if !can_infer {
-        debug!("Decoding \"{}\"", entry.file_path.display());
+    reader.seek(SeekFrom::Start(entry.data_offset(header)))?;
+
+    let mode = game.decrypt_mode();
+    let len = if header.encrypted {
+        let mut len = 0x1000;
 
-        let result = util::decode_text(&buffer);
-        if result.had_errors() {
-            log::warn!("Failed to cleanly decode file: {}", result.text());
+        if mode != DecryptMode::Lamento {
+            len += entry.un_decoded_file_path.len() as u32;
         }
 
-        buffer = result.text().as_bytes().to_vec();
+        entry.compressed_size.min(len)
+    } else {
+        0
+    };
+
+    let mut buffer = vec![0u8; len as usize];
+    reader.read_exact(&mut buffer)?;
+
+    if header.encrypted {
+        let key = decrypt_data(entry, header, game);
+        game.decryptor().transform_buffer(&mut buffer, key);
     }
 
-    Ok(buffer)
+    let rest = (entry.compressed_size - len) as u64;
+
+    Ok(std::io::Cursor::new(buffer).chain(reader.take(rest)))
+}
+
+/// How many entries [`detect_game`] checks before accepting a game
+const DETECTION_SAMPLES: usize = 4;
+
+/// Guesses which game the archive in `reader` is from
+///
+/// A few files with an extension are decrypted with each game's keys, a game matches if all of
+/// them come out as the kind of data their extension says (checked with [`infer`]), or as text for
+/// extensions [`infer`] doesn't know. Only the games from [`Game::candidates`] are tried, if there
+/// is just one it's returned without decrypting anything. The reader is rewound to the start of
+/// the archive afterwards.
+pub fn detect_game<R: Read + Seek>(reader: &mut R) -> Result<Game, NpaError> {
+    detect_game_with_progress(reader, |_| {})
+}
+
+/// Same as [`detect_game`], but calls `on_game` with each game before its keys are tried, so that
+/// slow detections can show what they're doing
+pub fn detect_game_with_progress<R: Read + Seek, F: FnMut(Game)>(
+    reader: &mut R,
+    mut on_game: F,
+) -> Result<Game, NpaError> {
+    let archive_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let header = parse_head(reader)?;
+    let table_start = reader.stream_position()?;
+
+    let candidates = Game::candidates(&header);
+    let only_candidate = candidates.len() == 1;
+    let mut found = None;
+
+    for game in candidates {
+        on_game(game);
+        reader.seek(SeekFrom::Start(table_start))?;
+
+        let Ok(entries) = read_entries(reader, &header, game.add_bytes_if_encrypted()) else {
+            continue;
+        };
+
+        if only_candidate {
+            found = Some(game);
+            break;
+        }
+
+        let mut samples: Vec<(&NpaEntry, String)> = entries
+            .iter()
+            .filter(|entry| !entry.is_directory() && entry.compressed_size > 0)
+            .filter_map(|entry| Some((entry, entry.extension()?)))
+            .collect();
+
+        // Entries with a recognizable signature are a much stronger signal than text
+        samples.sort_by_key(|(_, extension)| !infer::is_supported(extension));
+        samples.truncate(DETECTION_SAMPLES);
+
+        let matches = !samples.is_empty()
+            && samples.iter().all(|(entry, extension)| {
+                read_decrypted_data(
+                    reader,
+                    &header,
+                    entry,
+                    &game,
+                    SizePolicy::Lenient,
+                    archive_len,
+                )
+                .is_ok_and(|data| {
+                    if infer::is_supported(extension) {
+                        infer::is(&data, extension)
+                    } else {
+                        util::looks_like_text(&data)
+                    }
+                })
+            });
+
+        if matches {
+            found = Some(game);
+            break;
+        }
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+
+    let game = found.ok_or(NpaError::UnknownGame)?;
+    debug!("Detected game: {game:?}");
+
+    Ok(game)
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
         fs::{DirEntry, File},
+        io::Cursor,
         ops::Not,
         path::PathBuf,
     };
@@ -343,6 +1801,883 @@ mod tests {
 
     use super::*;
 
+    /// Builds an unencrypted, uncompressed archive in memory containing the given files
+    pub(crate) fn build_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        build_archive_for(files, None, false)
+    }
+
+    /// Builds an archive in memory containing the given files, encrypting the data for `game`
+    /// and compressing it if requested
+    fn build_archive_for(
+        files: &[(&str, &[u8])],
+        game: Option<&dyn GameKeys>,
+        compressed: bool,
+    ) -> Vec<u8> {
+        let mut header = NpaHead {
+            head: *b"NPA\x01\0\0\0",
+            key_1: 0x1234,
+            key_2: 0x5678,
+            encrypted: game.is_some(),
+            compressed,
+            file_count: files.len() as u32,
+            folder_count: 0,
+            total_count: files.len() as u32,
+            start: 0,
+        };
+
+        let add_bytes = game.is_some_and(|game| game.add_bytes_if_encrypted());
+
+        let mut table = Vec::new();
+        let mut data: Vec<u8> = Vec::new();
+
+        for (index, (name, contents)) in files.iter().enumerate() {
+            let mut stored = if compressed {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+                std::io::Write::write_all(&mut encoder, contents).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                contents.to_vec()
+            };
+
+            if let Some(game) = game {
+                let entry = NpaEntry {
+                    name_length: name.len() as u32,
+                    original_size: contents.len() as u32,
+                    un_decoded_file_path: name.as_bytes().to_vec(),
+                    ..Default::default()
+                };
+
+                encrypt(&mut stored, &entry, &header, game);
+            }
+
+            let encrypted_name: Vec<u8> = name
+                .bytes()
+                .enumerate()
+                .map(|(x, byte)| {
+                    byte.wrapping_sub(decrypt_header(x as u32, index as u32, &header, add_bytes))
+                })
+                .collect();
+
+            table.extend((encrypted_name.len() as u32).to_le_bytes());
+            table.extend(&encrypted_name);
+            table.push(0);
+            table.extend((index as u32).to_le_bytes());
+            table.extend((data.len() as u32).to_le_bytes());
+            table.extend((stored.len() as u32).to_le_bytes());
+            table.extend((contents.len() as u32).to_le_bytes());
+            data.extend(stored);
+        }
+
+        header.start = table.len() as u32;
+
+        let mut archive = header.head.to_vec();
+        archive.extend(header.key_1.to_le_bytes());
+        archive.extend(header.key_2.to_le_bytes());
+        archive.push(header.compressed as u8);
+        archive.push(header.encrypted as u8);
+        archive.extend(header.total_count.to_le_bytes());
+        archive.extend(header.folder_count.to_le_bytes());
+        archive.extend(header.file_count.to_le_bytes());
+        archive.extend([0u8; 8]);
+        archive.extend(header.start.to_le_bytes());
+        archive.extend(table);
+        archive.extend(data);
+
+        archive
+    }
+
+    /// Inverse of the data decryption in [`read_entry_data_generic`]
+    fn encrypt(data: &mut [u8], entry: &NpaEntry, header: &NpaHead, game: &dyn GameKeys) {
+        let table = game.encryption_key();
+        let mut inverse = [0u8; 256];
+        for (index, value) in table.iter().enumerate() {
+            inverse[*value as usize] = index as u8;
+        }
+
+        let key = decrypt_data(entry, header, game);
+        let mode = game.decrypt_mode();
+        let mut len = 0x1000;
+
+        if mode != DecryptMode::Lamento {
+            len += entry.un_decoded_file_path.len();
+        }
+
+        for (x, byte) in data.iter_mut().take(len).enumerate() {
+            *byte = match mode {
+                DecryptMode::Lamento => inverse[byte.wrapping_add(key) as usize],
+                DecryptMode::Totono => {
+                    let mut r = !byte.wrapping_add(key).wrapping_add(x as u8);
+                    r = inverse[r as usize];
+                    r = inverse[r as usize];
+                    inverse[r as usize]
+                }
+                DecryptMode::Standard => {
+                    inverse[byte.wrapping_add(key).wrapping_add(x as u8) as usize]
+                }
+            };
+        }
+    }
+
+    #[test]
+    fn test_read_entry_data_decrypts_every_game() {
+        let contents: Vec<u8> = (0..0x1100).map(|x| (x * 7) as u8).collect();
+
+        for game in Game::iter() {
+            let archive = build_archive_for(&[("image.png", &contents)], Some(&game), false);
+            let mut reader = Cursor::new(archive);
+
+            let head = parse_head(&mut reader).unwrap();
+            let entries = read_entries(&mut reader, &head, game.add_bytes_if_encrypted()).unwrap();
+            assert_eq!(entries[0].file_path, PathBuf::from("image.png"));
+
+            let data = read_entry_data(&mut reader, &head, &entries[0], game).unwrap();
+            assert_eq!(data, contents, "{game:?}");
+
+            let custom = CustomGame::from(game);
+            let data = read_entry_data_generic(
+                &mut reader,
+                &head,
+                &entries[0],
+                &custom,
+                TextMode::default(),
+            )
+            .unwrap();
+            assert_eq!(data, contents, "{game:?} as a custom game");
+        }
+    }
+
+    #[test]
+    fn test_read_entry_data_with_decryptor() {
+        /// Forwards to the game's own decryptor, counting the bytes it's asked to transform
+        struct Counting<'a> {
+            inner: Box<dyn Decryptor + 'a>,
+            calls: std::cell::Cell<usize>,
+        }
+
+        impl Decryptor for Counting<'_> {
+            fn transform(&self, byte: u8, index: u32, key: u8) -> u8 {
+                self.calls.set(self.calls.get() + 1);
+                self.inner.transform(byte, index, key)
+            }
+        }
+
+        let contents: Vec<u8> = (0..0x1100).map(|x| (x * 7) as u8).collect();
+        let game = Game::ChaosHead;
+        let archive = build_archive_for(&[("image.png", &contents)], Some(&game), false);
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+
+        let counting = Counting {
+            inner: game.decryptor(),
+            calls: Default::default(),
+        };
+        let custom = WithDecryptor {
+            game: &game,
+            decryptor: &counting,
+        };
+
+        let data = read_entry_data_generic(&mut reader, &head, &entries[0], &custom, TextMode::Raw)
+            .unwrap();
+
+        assert_eq!(data, contents);
+        assert_eq!(counting.calls.get(), 0x1000 + "image.png".len());
+    }
+
+    #[test]
+    fn test_read_entry_data_with_key() {
+        let mut key = [0u8; 256];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(5).wrapping_add(3);
+        }
+
+        let game = CustomGame {
+            key,
+            ..CustomGame::from(Game::ChaosHead)
+        };
+
+        let contents: Vec<u8> = (0..0x1100).map(|x| (x * 7) as u8).collect();
+        let archive = build_archive_for(&[("image.png", &contents)], Some(&game), false);
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+
+        let data =
+            read_entry_data_with_key(&mut reader, &head, &entries[0], &key, DecryptMode::Standard)
+                .unwrap();
+        assert_eq!(data, contents);
+
+        let data = read_entry_data(&mut reader, &head, &entries[0], Game::ChaosHead).unwrap();
+        assert_ne!(data, contents);
+    }
+
+    #[test]
+    fn test_read_entry_data_decompresses_decrypted_data() {
+        // Pseudo-random bytes so the compressed stream is longer than the encrypted window
+        let mut state = 0x2545_f491u32;
+        let first: Vec<u8> = (0..0x2000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let second = b"second entry, right after the first one in the data section";
+
+        for game in Game::iter() {
+            let files: [(&str, &[u8]); 2] = [("image.png", &first), ("other.png", second)];
+            let archive = build_archive_for(&files, Some(&game), true);
+            let mut reader = Cursor::new(archive);
+
+            let head = parse_head(&mut reader).unwrap();
+            let entries = read_entries(&mut reader, &head, game.add_bytes_if_encrypted()).unwrap();
+            assert!(entries[0].compressed_size > 0x1000);
+
+            for (entry, (_, contents)) in entries.iter().zip(files) {
+                let data = read_entry_data(&mut reader, &head, entry, game).unwrap();
+                assert_eq!(data, contents, "{game:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_entry() {
+        let archive = build_archive(&[("a.txt", b"a"), ("Scripts\\Main.scr", b"main")]);
+        let mut archive = NpaArchive::open(Cursor::new(archive), Game::ChaosHead).unwrap();
+
+        for path in ["Scripts/Main.scr", "scripts/main.scr", "SCRIPTS\\main.SCR"] {
+            let entry = find_entry(archive.entries(), Path::new(path)).unwrap();
+            assert_eq!(entry.file_path, PathBuf::from("Scripts/Main.scr"), "{path}");
+        }
+
+        assert!(find_entry(archive.entries(), Path::new("main.scr")).is_none());
+        assert!(find_entry(archive.entries(), Path::new("scripts")).is_none());
+
+        let data = archive.read_path(Path::new("scripts/main.scr"), Game::ChaosHead);
+        assert_eq!(data.unwrap(), b"main");
+
+        let err = archive.read_path(Path::new("missing.txt"), Game::ChaosHead);
+        assert!(matches!(err, Err(NpaError::EntryNotFound(_))));
+    }
+
+    #[test]
+    fn test_detect_game() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.resize(0x200, 0);
+        let files: [(&str, &[u8]); 2] = [("image.png", &png), ("script.nss", b"call main;\r\n")];
+
+        for game in Game::iter() {
+            let archive = build_archive_for(&files, Some(&game), false);
+            let mut reader = Cursor::new(archive);
+
+            // Some games share all of their parameters, any of them reads the archive correctly
+            let detected = detect_game(&mut reader).unwrap();
+            assert_eq!(
+                CustomGame::from(detected),
+                CustomGame::from(game),
+                "{game:?}"
+            );
+            assert_eq!(reader.position(), 0);
+        }
+
+        let unknown = CustomGame {
+            key: std::array::from_fn(|i| (i as u8).rotate_left(3)),
+            ..CustomGame::from(Game::ChaosHead)
+        };
+
+        let archive = build_archive_for(&files, Some(&unknown), false);
+        let mut reader = Cursor::new(archive);
+        let header = parse_head(&mut reader).unwrap();
+
+        let mut tried = Vec::new();
+        let err = detect_game_with_progress(&mut reader, |game| tried.push(game)).unwrap_err();
+        assert!(matches!(err, NpaError::UnknownGame));
+        assert_eq!(tried, Game::candidates(&header));
+    }
+
+    #[test]
+    fn test_read_entry_splits_both_separators() {
+        let mut reader = Cursor::new(build_archive(&[("data/ui\\button.png", b"png")]));
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+
+        let components: Vec<_> = entries[0].file_path.components().collect();
+        assert_eq!(components.len(), 3);
+        assert_eq!(
+            entries[0].file_path,
+            Path::new("data").join("ui").join("button.png")
+        );
+    }
+
+    #[test]
+    fn test_detect_header_key_mode() {
+        let files: [(&str, &[u8]); 3] = [
+            ("system/config.dat", b"a"),
+            ("bgm01.ogg", b"b"),
+            ("スクリプト.nss", b"c"),
+        ];
+
+        for (game, expected) in [
+            (None, false),
+            (Some(Game::ChaosHead), false),
+            (Some(Game::Lamento), true),
+        ] {
+            let archive =
+                build_archive_for(&files, game.as_ref().map(|g| g as &dyn GameKeys), false);
+            let mut reader = Cursor::new(archive);
+            let head = parse_head(&mut reader).unwrap();
+
+            assert_eq!(
+                detect_header_key_mode(&mut reader, &head).unwrap(),
+                expected
+            );
+
+            let entries = read_entries_auto(&mut reader, &head).unwrap();
+            let paths: Vec<_> = entries.iter().map(|e| e.file_path.clone()).collect();
+            assert_eq!(
+                paths,
+                files.map(|(name, _)| PathBuf::from(name)),
+                "{game:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_entry_flags_lossy_names() {
+        let mut reader = Cursor::new(build_archive(&[("a.txt", b"a")]));
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+        assert!(!entries[0].decode_lossy);
+
+        // 0x81 starts a two-byte Shift JIS sequence that 0x20 can't finish
+        let mut reader = Cursor::new(build_archive(&[("a\u{81} .txt", b"a")]));
+        let head = parse_head(&mut reader).unwrap();
+        let forced = TextEncoding::for_label("shift_jis").unwrap();
+        let entries =
+            read_entries_with_progress(&mut reader, &head, false, forced, |_| {}).unwrap();
+        assert!(entries[0].decode_lossy);
+    }
+
+    #[test]
+    fn test_entry_extension_and_file_name() {
+        let entry = |path: &str| NpaEntry {
+            file_path: PathBuf::from(path),
+            ..Default::default()
+        };
+
+        assert_eq!(entry("bg/Title.PNG").extension().as_deref(), Some("png"));
+        assert_eq!(
+            entry("bg/Title.PNG").file_name(),
+            Some(OsStr::new("Title.PNG"))
+        );
+        assert_eq!(entry("system/README").extension(), None);
+        assert_eq!(entry("").file_name(), None);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_open_mmap() {
+        let path = std::env::temp_dir().join(format!("cat-nipa-mmap-{}.npa", std::process::id()));
+        std::fs::write(
+            &path,
+            build_archive(&[("a.txt", b"first"), ("sub\\b.txt", b"second")]),
+        )
+        .unwrap();
+
+        let archive = NpaArchive::open_mmap(&path, Game::ChaosHead).unwrap();
+        let read = std::sync::Mutex::new(Vec::new());
+
+        archive
+            .for_each_entry_shared(|_, entry, reader| {
+                let data = read_entry_data(reader, archive.header(), entry, Game::ChaosHead)?;
+                read.lock().unwrap().push((entry.file_path.clone(), data));
+                Ok(())
+            })
+            .unwrap();
+
+        let mut read = read.into_inner().unwrap();
+        read.sort();
+        assert_eq!(
+            read,
+            [
+                (PathBuf::from("a.txt"), b"first".to_vec()),
+                (PathBuf::from("sub/b.txt"), b"second".to_vec())
+            ]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_game_serde_matches_value_enum() {
+        use clap::ValueEnum;
+
+        for game in Game::iter() {
+            let name = game.to_possible_value().unwrap().get_name().to_string();
+            let json = serde_json::to_string(&game).unwrap();
+
+            assert_eq!(json, format!("\"{name}\""));
+            assert_eq!(serde_json::from_str::<Game>(&json).unwrap(), game);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_mode_games() {
+        assert_eq!(
+            DecryptMode::Lamento.games(),
+            [Game::Lamento, Game::LamentoTrail]
+        );
+        assert_eq!(DecryptMode::Totono.games(), [Game::Totono]);
+
+        let total: usize = DecryptMode::iter().map(|mode| mode.games().len()).sum();
+        assert_eq!(total, Game::iter().count());
+    }
+
+    #[test]
+    fn test_game_display() {
+        use clap::ValueEnum;
+
+        let game = Game::DramaticalMurderReConnect;
+        assert_eq!(game.to_string(), "DRAMAtical Murder re:connect");
+        assert_eq!(
+            game.to_possible_value().unwrap().get_name(),
+            "dramatical-murder-re-connect"
+        );
+    }
+
+    #[test]
+    fn test_game_candidates() {
+        let files: [(&str, &[u8]); 1] = [("a.txt", b"text")];
+        let mut reader = Cursor::new(build_archive_for(&files, None, false));
+        let mut header = parse_head(&mut reader).unwrap();
+
+        assert_eq!(Game::candidates(&header), [Game::ChaosHead]);
+
+        // Compression doesn't change how the data is decrypted, so it doesn't matter either way
+        header.compressed = true;
+        assert_eq!(Game::candidates(&header), [Game::ChaosHead]);
+
+        header.encrypted = true;
+        let candidates = Game::candidates(&header);
+
+        assert!(candidates.contains(&Game::Lamento));
+        assert!(!candidates.contains(&Game::LamentoTrail));
+        assert_eq!(candidates.len(), Game::iter().count() - 1);
+        assert_eq!(candidates[0], Game::ChaosHead);
+
+        header.compressed = false;
+        assert_eq!(Game::candidates(&header), candidates);
+
+        // No game is known to use fixed header keys, so they don't narrow anything down
+        header.key_1 = 0x4147_414E;
+        header.key_2 = 0x2100_0000;
+        assert_eq!(Game::candidates(&header), candidates);
+    }
+
+    #[test]
+    fn test_read_entry_data_to_matches_read_entry_data() {
+        let image: Vec<u8> = (0..0x30000).map(|x| (x / 7) as u8).collect();
+        let files: [(&str, &[u8]); 2] = [("big.png", &image), ("text.txt", "テキスト".as_bytes())];
+
+        for compressed in [false, true] {
+            let archive = build_archive_for(&files, Some(&Game::Totono), compressed);
+            let mut reader = Cursor::new(archive);
+
+            let head = parse_head(&mut reader).unwrap();
+            let entries = read_entries(&mut reader, &head, false).unwrap();
+
+            for entry in &entries {
+                let data = read_entry_data(&mut reader, &head, entry, Game::Totono).unwrap();
+
+                let mut streamed = Vec::new();
+                let written =
+                    read_entry_data_to(&mut reader, &head, entry, Game::Totono, &mut streamed)
+                        .unwrap();
+
+                assert_eq!(streamed, data);
+                assert_eq!(written, data.len() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn test_raw_text_mode_keeps_data() {
+        // Without control bytes, so that it still looks like malformed text to the default mode
+        let binary = [0x82, 0xa0, 0xff, 0x81];
+        let archive = build_archive(&[("blob.dat", &binary), ("noext", b"abc")]);
+
+        let mut archive = NpaArchive::open(Cursor::new(archive), Game::ChaosHead).unwrap();
+        let entries = archive.entries().to_vec();
+        assert_ne!(archive.read(&entries[0], Game::ChaosHead).unwrap(), binary);
+
+        let mut archive = archive.with_text_mode(TextMode::Raw);
+        assert_eq!(archive.read(&entries[0], Game::ChaosHead).unwrap(), binary);
+        assert_eq!(archive.read(&entries[1], Game::ChaosHead).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_read_entry_data_every_flag_combination() {
+        let binary: Vec<u8> = (0..=255).cycle().take(0x1800).collect();
+        let shift_jis = encoding_rs::SHIFT_JIS.encode("テキスト").0.into_owned();
+        let files: [(&str, &[u8]); 3] = [
+            ("blob", &binary),
+            ("image.png", &binary),
+            ("script.nss", &shift_jis),
+        ];
+
+        for game in [None, Some(&Game::ChaosHead as &dyn GameKeys)] {
+            for compressed in [false, true] {
+                let archive = build_archive_for(&files, game, compressed);
+                let mut reader = Cursor::new(archive);
+
+                let head = parse_head(&mut reader).unwrap();
+                assert_eq!(
+                    (head.encrypted, head.compressed),
+                    (game.is_some(), compressed)
+                );
+
+                let entries = read_entries(&mut reader, &head, false).unwrap();
+                let read = |reader: &mut Cursor<Vec<u8>>, index: usize| {
+                    read_entry_data(reader, &head, &entries[index], Game::ChaosHead).unwrap()
+                };
+
+                // Binary data comes out verbatim with or without an extension, text is converted
+                assert_eq!(read(&mut reader, 0), binary);
+                assert_eq!(read(&mut reader, 1), binary);
+                assert_eq!(read(&mut reader, 2), "テキスト".as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn test_forced_text_encoding() {
+        let utf16: Vec<u8> = "script".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let archive = build_archive(&[("op.txt", &utf16)]);
+
+        // Names are usually Shift JIS even when the scripts aren't, so only the data is forced
+        let encoding = TextEncoding::for_label("utf-16le").unwrap();
+        let mut archive = NpaArchive::open(Cursor::new(archive), Game::ChaosHead)
+            .unwrap()
+            .with_encoding(encoding);
+        let entry = archive.entries()[0].clone();
+
+        assert_eq!(archive.read(&entry, Game::ChaosHead).unwrap(), b"script");
+
+        // The UTF-16 bytes of ASCII text are valid UTF-8, so they're kept as they are by default
+        let mut archive = archive.with_encoding(TextEncoding::Detect);
+        assert_eq!(archive.read(&entry, Game::ChaosHead).unwrap(), utf16);
+
+        assert!(TextEncoding::for_label("not an encoding").is_none());
+    }
+
+    #[test]
+    fn test_read_entries_reuses_name_buffer() {
+        let archive = build_archive(&[("long_name.txt", b"a"), ("b.txt", b"b")]);
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+
+        assert_eq!(entries[0].file_path, PathBuf::from("long_name.txt"));
+        assert_eq!(entries[0].un_decoded_file_path, b"long_name.txt");
+        assert_eq!(entries[1].file_path, PathBuf::from("b.txt"));
+        assert_eq!(entries[1].un_decoded_file_path, b"b.txt");
+    }
+
+    #[test]
+    fn test_read_entry_data_keeps_valid_utf8() {
+        let contents = "{\"name\": \"カオスヘッド\", \"ü\": [1, 2, 3]}".as_bytes();
+        let archive = build_archive(&[("data.json", contents)]);
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+        let data = read_entry_data(&mut reader, &head, &entries[0], Game::ChaosHead).unwrap();
+
+        assert_eq!(data, contents);
+    }
+
+    #[test]
+    fn test_read_entries_rejects_data_in_entry_table() {
+        let mut archive = build_archive(&[("a.txt", b"a"), ("b.txt", b"b")]);
+
+        // Claim an empty table, so the data of the first entry starts where its record does
+        archive[0x25..0x29].copy_from_slice(&0u32.to_le_bytes());
+
+        let mut reader = Cursor::new(archive);
+        let head = parse_head(&mut reader).unwrap();
+        let err = read_entries(&mut reader, &head, false).unwrap_err();
+
+        let NpaError::Entry { path, source } = err else {
+            panic!("expected an entry error, got {err:?}");
+        };
+        assert_eq!(path, PathBuf::from("a.txt"));
+        assert!(
+            matches!(
+                *source,
+                NpaError::DataOverlapsTable { offset, table_end }
+                    if offset == NPA_HEADER_SIZE && table_end == NPA_HEADER_SIZE + 4 + 5 + 1 + 16
+            ),
+            "{source:?}"
+        );
+    }
+
+    #[test]
+    fn test_read_entry_data_without_extension() {
+        let mut reader = Cursor::new(build_archive(&[("system/README", "読んで".as_bytes())]));
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+
+        let data = read_entry_data(&mut reader, &head, &entries[0], Game::ChaosHead).unwrap();
+        assert_eq!(data, "読んで".as_bytes());
+    }
+
+    #[test]
+    fn test_read_entry_data_errors() {
+        let mut archive = build_archive(&[("b.txt", b"de")]);
+        archive.pop();
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+
+        let err = read_entry_data(&mut reader, &head, &entries[0], Game::ChaosHead).unwrap_err();
+        assert!(matches!(
+            err,
+            NpaError::SizeMismatch {
+                expected: 2,
+                got: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn test_entries_iterator() {
+        let archive = build_archive(&[("a.txt", b"a"), ("sub\\b.txt", b"b")]);
+        let mut reader = Cursor::new(archive.clone());
+        let head = parse_head(&mut reader).unwrap();
+
+        let lazy: Vec<_> = entries(&mut reader, &head, false)
+            .map(|entry| entry.unwrap().file_path)
+            .collect();
+        assert_eq!(lazy, [PathBuf::from("a.txt"), PathBuf::from("sub/b.txt")]);
+
+        // The table runs out in the middle of the second entry
+        let first_entry = 4 + "a.txt".len() + 1 + 4 * 4;
+        let mut reader =
+            Cursor::new(archive[..NPA_HEADER_SIZE as usize + first_entry + 4].to_vec());
+        let head = parse_head(&mut reader).unwrap();
+        let mut iter = entries(&mut reader, &head, false);
+
+        assert!(iter.next().unwrap().is_ok());
+        assert!(matches!(
+            iter.next(),
+            Some(Err(NpaError::TruncatedEntryTable {
+                expected: 2,
+                read: 1
+            }))
+        ));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_read_entries_rejects_inflated_count() {
+        let mut archive = build_archive(&[("a.txt", b"a"), ("b.txt", b"b")]);
+        archive[0x11..0x15].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut reader = Cursor::new(archive);
+        let head = parse_head(&mut reader).unwrap();
+        assert_eq!(head.total_count, u32::MAX);
+
+        // The data section follows the table and gets parsed as further entries until it runs out
+        let err = read_entries(&mut reader, &head, false).unwrap_err();
+        assert!(matches!(
+            err,
+            NpaError::TruncatedEntryTable {
+                expected: u32::MAX,
+                read
+            } if read >= 2
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        let archive = build_archive_for(&[("a.png", b"abcdef")], None, true);
+        let archive_len = archive.len() as u64;
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+        assert!(entries[0].validate(&head, archive_len).is_ok());
+
+        let mut past_end = entries[0].clone();
+        past_end.offset += 1;
+        assert!(matches!(
+            past_end.validate(&head, archive_len),
+            Err(NpaError::SizeMismatch { got, .. }) if got == past_end.compressed_size as u64 - 1
+        ));
+
+        let mut huge = entries[0].clone();
+        huge.original_size = u32::MAX;
+        assert!(matches!(
+            huge.validate(&head, archive_len),
+            Err(NpaError::ImplausibleSize { .. })
+        ));
+
+        let err = read_entry_data(&mut reader, &head, &huge, Game::ChaosHead).unwrap_err();
+        assert!(matches!(err, NpaError::ImplausibleSize { .. }));
+    }
+
+    #[test]
+    fn test_read_entry_data_truncates_to_original_size() {
+        let contents = b"0123456789abcdef";
+        let archive = build_archive_for(&[("data.png", contents)], None, true);
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let mut entries = read_entries(&mut reader, &head, false).unwrap();
+        entries[0].original_size = 10;
+
+        let data = read_entry_data(&mut reader, &head, &entries[0], Game::ChaosHead).unwrap();
+        assert_eq!(data, &contents[..10]);
+
+        let mut archive =
+            NpaArchive::from_parts(reader, head, entries).with_size_policy(SizePolicy::Strict);
+        let entry = archive.entries()[0].clone();
+
+        let err = archive.read(&entry, Game::ChaosHead).unwrap_err();
+        assert!(matches!(
+            err,
+            NpaError::SizeMismatch {
+                expected: 10,
+                got: 16
+            }
+        ));
+    }
+
+    #[test]
+    fn test_read_entry_prefix() {
+        let contents: Vec<u8> = (0..0x2000u32).map(|x| x.wrapping_mul(x) as u8).collect();
+
+        for compressed in [false, true] {
+            let game = Game::ChaosHead;
+            let archive = build_archive_for(&[("data.png", &contents)], Some(&game), compressed);
+            let mut reader = Cursor::new(archive);
+
+            let head = parse_head(&mut reader).unwrap();
+            let entries = read_entries(&mut reader, &head, false).unwrap();
+
+            let prefix = read_entry_prefix(&mut reader, &head, &entries[0], &game, 16).unwrap();
+            assert_eq!(prefix, contents[..16]);
+        }
+    }
+
+    #[test]
+    fn test_read_entry_data_stream_ends_early() {
+        let contents: Vec<u8> = (0..0x4000u32).map(|x| x.wrapping_mul(x) as u8).collect();
+        let archive = build_archive_for(&[("data.png", &contents)], None, true);
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let mut entries = read_entries(&mut reader, &head, false).unwrap();
+
+        // Only the first half of the zlib stream is read, the way it is when decryption went wrong
+        entries[0].compressed_size /= 2;
+
+        let data = read_entry_data(&mut reader, &head, &entries[0], Game::ChaosHead).unwrap();
+        assert!(data.len() < contents.len());
+        assert!(contents.starts_with(&data));
+
+        let mut archive =
+            NpaArchive::from_parts(reader, head, entries).with_size_policy(SizePolicy::Strict);
+        let entry = archive.entries()[0].clone();
+
+        let err = archive.read(&entry, Game::ChaosHead).unwrap_err();
+        assert!(matches!(
+            err,
+            NpaError::SizeMismatch { expected: 0x4000, got } if got == data.len() as u64
+        ));
+    }
+
+    #[test]
+    fn test_read_entries_reports_progress() {
+        let archive = build_archive(&[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")]);
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let mut progress = Vec::new();
+        read_entries_with_progress(&mut reader, &head, false, TextEncoding::Detect, |read| {
+            progress.push(read)
+        })
+        .unwrap();
+
+        assert_eq!(progress, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_archive_info() {
+        let archive = build_archive(&[("a.txt", b"abc"), ("b.txt", b"de")]);
+        let mut reader = Cursor::new(archive);
+
+        let head = parse_head(&mut reader).unwrap();
+        let entries = read_entries(&mut reader, &head, false).unwrap();
+
+        let info = ArchiveInfo::new(&head, &entries, Some(Game::ChaosHead));
+        assert_eq!(info.file_count, 2);
+        assert_eq!(info.folder_count, 0);
+        assert_eq!(info.original_size, 5);
+        assert!(info.warnings.is_empty());
+
+        let info = ArchiveInfo::new(&head, &entries[..1], None);
+        assert_eq!(info.warnings.len(), 2);
+    }
+
+    #[test]
+    fn test_entry_totals_skip_directories() {
+        let entries = [
+            NpaEntry {
+                original_size: 10,
+                ..Default::default()
+            },
+            NpaEntry {
+                type_: 1,
+                original_size: 100,
+                ..Default::default()
+            },
+            NpaEntry {
+                original_size: 5,
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(total_original_size(&entries), 15);
+        assert_eq!(file_count_actual(&entries), 2);
+        assert_eq!(total_original_size(&entries[1..2]), 0);
+    }
+
+    #[test]
+    fn test_archive_open_and_read() {
+        let files: [(&str, &[u8]); 2] = [("a.txt", b"abc"), ("dir/b.txt", b"de")];
+        let archive = build_archive_for(&files, Some(&Game::ChaosHead), false);
+
+        let archive_len = archive.len() as u64;
+
+        let mut archive = NpaArchive::open(Cursor::new(archive), Game::ChaosHead).unwrap();
+        assert_eq!(archive.header().total_count, 2);
+        assert_eq!(archive.archive_len(), Some(archive_len));
+        assert_eq!(archive.info().original_size, 5);
+        assert_eq!(archive.info().game, Some(Game::ChaosHead));
+
+        for (entry, (name, contents)) in archive.entries().to_vec().iter().zip(files) {
+            assert_eq!(entry.file_path, PathBuf::from(name));
+            assert_eq!(archive.read(entry, Game::ChaosHead).unwrap(), contents);
+        }
+    }
+
     fn archives() -> impl Iterator<Item = DirEntry> {
         let test_dir = PathBuf::from(format!(
             "{}/test_data/",
@@ -381,18 +2716,11 @@ mod tests {
                 });
 
                 file_entry.is_some_and(|entry| {
-                    if entry.file_path.extension().is_none() {
+                    let Some(extension) = entry.extension() else {
                         log::warn!("No extension for {:?}", entry.file_path.to_string_lossy());
 
                         return false;
-                    }
-
-                    let extension = entry
-                        .file_path
-                        .extension()
-                        .expect("Entry has no extension")
-                        .to_string_lossy()
-                        .to_lowercase();
+                    };
 
                     let data = read_entry_data(&mut reader, &head, &entry, game);
 
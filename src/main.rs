@@ -1,69 +1,1214 @@
 use std::{
+    collections::HashSet,
     fs,
+    io::{self, IsTerminal, Seek, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    sync::Mutex,
 };
 
-use cat_nipa::{Game, parse_head, read_entries, read_entry_data};
-use clap::Parser;
-use indicatif::ProgressBar;
+#[cfg(feature = "serde")]
+use cat_nipa::ArchiveInfo;
+use cat_nipa::{
+    CustomGame, EntryOrder, ExtractOptions, ExtractReport, Game, GameKeys, ImageFormat,
+    ManifestEntry, Mismatch, NPA_HEADER_SIZE, NpaArchive, NpaEntry, NpaError, NpaHead, NpaWriter,
+    Overwrite, SizePolicy, TextEncoding, TextMode, compare_manifests, detect_game,
+    detect_game_with_progress, detect_header_key_mode, extract_all_parallel_with_progress,
+    extract_all_with_progress, file_count_actual, find_entry, parse_head,
+    read_entries_with_progress, read_entry_data_to_generic, read_entry_prefix, read_manifest,
+    sanitize_path, select_entries, total_original_size, write_manifest,
+};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use indicatif::{ProgressBar, ProgressStyle};
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+#[cfg(feature = "fuse")]
+mod fuse;
+mod tui;
+
+/// Archives with fewer files than this are extracted serially, as spinning up the thread pool
+/// and a file handle per thread isn't worth it for them
+const PARALLEL_THRESHOLD: usize = 64;
 
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    file: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
 
+    #[arg(required = true)]
+    file: Option<PathBuf>,
+
+    /// Game the archive is from. Detected if not given, and asked for interactively if that fails
     #[arg(value_enum, short, long)]
-    game: Game,
+    game: Option<Game>,
+
+    /// Detect which game the archive is from when listing it too, which listing doesn't need
+    /// unless `--verbose` should show the MIME types of an encrypted archive
+    #[arg(long, conflicts_with = "game")]
+    auto: bool,
+
+    /// Decrypt the data with the 256-byte substitution table in this file instead of the game's
+    /// own, for trying out the table of a title that isn't supported yet
+    #[arg(long, value_name = "FILE", value_parser = parse_key, conflicts_with = "tui")]
+    key: Option<[u8; 256]>,
 
     #[arg(short, long = "output")]
     output_dir: Option<PathBuf>,
+
+    /// Only extract the entry at this path inside the archive, matched ignoring case and with either
+    /// separator
+    #[arg(long = "file", value_name = "PATH", requires = "stdout")]
+    entry_path: Option<PathBuf>,
+
+    /// Write the entry picked with `--file` to stdout, without progress bars or log output
+    #[arg(long, requires = "entry_path", conflicts_with_all = ["output_dir", "tui", "list", "zip"])]
+    stdout: bool,
+
+    /// Print how many files and bytes would be extracted and whether their entries look valid,
+    /// without reading any data or writing anything
+    #[arg(long, conflicts_with_all = ["tui", "list", "stdout"])]
+    dry_run: bool,
+
+    /// Write every file directly into the output directory, appending the file ID to names that
+    /// would collide
+    #[arg(long)]
+    flatten: bool,
+
+    /// Overwrite files that already exist in the output directory instead of skipping them
+    #[arg(long)]
+    force: bool,
+
+    /// Write the extracted entries into this ZIP file instead of a directory
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["output_dir", "tui", "reencode_images"])]
+    zip: Option<PathBuf>,
+
+    /// Write the CRC-32 and size of every selected file's extracted data to this CSV file instead
+    /// of extracting it
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["tui", "list", "stdout", "dry_run", "zip", "reencode_images"]
+    )]
+    manifest: Option<PathBuf>,
+
+    /// Compare the extracted data of every selected file against a manifest written with
+    /// `--manifest`, listing the files that differ, instead of extracting anything
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["tui", "list", "stdout", "dry_run", "zip", "reencode_images", "manifest"]
+    )]
+    verify: Option<PathBuf>,
+
+    /// Only extract the first N files in `--sort` order, useful for checking that the right game
+    /// was picked
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Order the selected files are extracted in, so that `--sort size --limit 10` extracts the 10
+    /// biggest
+    #[arg(long, value_enum, value_name = "ORDER", default_value_t = EntryOrder::Table)]
+    sort: EntryOrder,
+
+    /// Prefix extracted file names with their zero-padded position in the entry table
+    #[arg(long)]
+    number_prefix: bool,
+
+    /// Re-encode image entries to this format while extracting, other entries are written as is
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        visible_alias = "image-format"
+    )]
+    reencode_images: Option<ImageFormat>,
+
+    /// Quality used when re-encoding images to JPEG
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+
+    /// Keep re-encoded images the way their pixels are stored, instead of rotating them upright
+    /// according to their orientation metadata
+    #[arg(long, requires = "reencode_images")]
+    no_auto_orient: bool,
+
+    /// Also write log output to this file, defaulting the log level to warnings if `RUST_LOG` is unset
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Convert text files to UTF-8, otherwise every file is written exactly as it's stored
+    #[arg(long)]
+    decode_text: bool,
+
+    /// Encoding of text files, such as `shift_jis`, `euc-jp` or `utf-16le`, detected separately for
+    /// each file if not given. Implies `--decode-text`
+    #[arg(long, value_name = "LABEL", value_parser = parse_encoding)]
+    encoding: Option<TextEncoding>,
+
+    /// Encoding of the file names in the entry table, detected separately for each name if not given
+    #[arg(long, value_name = "LABEL", value_parser = parse_encoding)]
+    name_encoding: Option<TextEncoding>,
+
+    /// Only extract entries whose path matches this glob, such as `*.png` or `scripts/*`. Can be
+    /// given more than once, paths use `/` as the separator and are matched ignoring case
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    filter: Vec<Glob>,
+
+    /// Skip entries whose path matches this glob, even if they match `--filter`
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    exclude: Vec<Glob>,
+
+    /// Fail to extract files that don't decompress to the size the entry table says, instead of
+    /// warning and writing what was decompressed
+    #[arg(long)]
+    strict_sizes: bool,
+
+    /// Exit with an error if any file name contains bytes that aren't valid in its encoding,
+    /// instead of extracting it with those bytes replaced
+    #[arg(long)]
+    strict_names: bool,
+
+    /// Extract files one at a time instead of in parallel, same as `--threads 1`
+    #[arg(long)]
+    serial: bool,
+
+    /// Number of threads extracting files, defaulting to the number of logical CPUs. With more than
+    /// one, files are written in no particular order
+    #[arg(long, value_name = "N", conflicts_with = "serial")]
+    threads: Option<NonZeroUsize>,
+
+    /// Browse the archive interactively in the terminal instead of extracting everything
+    #[arg(long)]
+    tui: bool,
+
+    /// Print the entries of the archive with their sizes instead of extracting them
+    #[arg(short, long, conflicts_with = "tui")]
+    list: bool,
+
+    /// With `--list`, also show whether each name decoded cleanly, the MIME type of the data and
+    /// whether `infer` knows the extension. The MIME type of encrypted archives needs `--game` or
+    /// `--auto`
+    #[arg(short, long, requires = "list")]
+    verbose: bool,
+
+    /// Print the header and entries of the archive as JSON instead of extracting them
+    #[cfg(feature = "serde")]
+    #[arg(long, conflicts_with_all = ["tui", "list"])]
+    json: bool,
 }
 
-fn main() {
-    env_logger::init();
+#[derive(Subcommand)]
+enum Command {
+    /// Print a game's 256-byte encryption key table in hex
+    #[command(hide = true)]
+    DumpKey {
+        #[arg(value_enum)]
+        game: Game,
+    },
+
+    /// Pack the files of a directory into a new archive
+    Pack {
+        directory: PathBuf,
+
+        output: PathBuf,
+
+        /// Zlib-compress the data of each file
+        #[arg(long)]
+        compress: bool,
+    },
+
+    /// Mount an archive as a read-only file system, decrypting entries as they are read
+    #[cfg(feature = "fuse")]
+    Mount {
+        file: PathBuf,
+
+        mountpoint: PathBuf,
+
+        #[arg(value_enum, short, long)]
+        game: Game,
+    },
+}
+
+/// Writes log output to both stderr and a file
+struct LogTee(fs::File);
 
+impl Write for LogTee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.0.write_all(buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.0.flush()
+    }
+}
+
+fn main() {
     let args = Args::parse();
 
-    let file = std::fs::File::open(&args.file).unwrap();
+    match &args.log_file {
+        Some(path) => {
+            let file = fs::File::create(path).unwrap_or_else(|err| {
+                exit_with_error(
+                    format!("Failed to create the log file \"{}\"", path.display()),
+                    err,
+                )
+            });
+
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"))
+                .target(env_logger::Target::Pipe(Box::new(LogTee(file))))
+                .init();
+        }
+        None => env_logger::init(),
+    }
+
+    // Nothing but the entry may end up on stdout, and stderr shouldn't fill up when piping either
+    if args.stdout {
+        log::set_max_level(log::LevelFilter::Off);
+    }
+
+    match args.command {
+        Some(Command::DumpKey { game }) => {
+            dump_key(game);
+            return;
+        }
+
+        Some(Command::Pack {
+            directory,
+            output,
+            compress,
+        }) => {
+            pack(&directory, &output, compress)
+                .unwrap_or_else(|err| exit_with_error("Failed to pack the archive", err));
+            return;
+        }
+
+        #[cfg(feature = "fuse")]
+        Some(Command::Mount {
+            file,
+            mountpoint,
+            game,
+        }) => {
+            let archive = fs::File::open(&file)
+                .map_err(NpaError::from)
+                .and_then(|file| NpaArchive::open(io::BufReader::new(file), game))
+                .unwrap_or_else(|err| exit_with_error("Failed to open the archive", err));
+
+            fuse::mount(archive, game, &file, &mountpoint)
+                .unwrap_or_else(|err| exit_with_error("Failed to mount the archive", err));
+            return;
+        }
+
+        None => {}
+    }
+
+    let input = args
+        .file
+        .expect("clap requires a file without a subcommand");
+
+    let file = std::fs::File::open(&input).unwrap_or_else(|err| {
+        exit_with_error(format!("Failed to open \"{}\"", input.display()), err)
+    });
     let mut reader = std::io::BufReader::new(file);
 
-    let header = parse_head(&mut reader).unwrap();
+    #[cfg(feature = "serde")]
+    let json = args.json;
+    #[cfg(not(feature = "serde"))]
+    let json = false;
+
+    if args.list || json {
+        let name_encoding = args.name_encoding.unwrap_or_default();
+        let result = read_table(&mut reader, args.game, args.auto, name_encoding).and_then(
+            |(header, entries, game)| {
+                #[cfg(feature = "serde")]
+                if json {
+                    return print_manifest(&ArchiveInfo::new(&header, &entries, game), &entries);
+                }
 
-    let entries = read_entries(
+                if args.verbose {
+                    list_entries_verbose(&mut reader, &header, &entries, game)
+                } else {
+                    list_entries(&header, &entries)
+                }
+            },
+        );
+
+        if let Err(err) = result {
+            exit_with_error(format!("Failed to list \"{}\"", input.display()), err);
+        }
+
+        return;
+    }
+
+    let header = parse_head(&mut reader)
+        .unwrap_or_else(|err| exit_with_error("Failed to parse the archive header", err));
+
+    let game = if let Some(game) = args.game {
+        game
+    } else {
+        let spinner = if args.stdout {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new_spinner()
+        };
+        spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+        let detected = detect_game_with_progress(&mut reader, |game| {
+            spinner.set_message(format!("Detecting the game, trying {game}…"))
+        });
+        spinner.finish_and_clear();
+
+        match detected {
+            Ok(game) => {
+                if !args.stdout {
+                    eprintln!("Detected game: {game}");
+                }
+
+                game
+            }
+            Err(NpaError::UnknownGame) => {
+                eprintln!("Couldn't detect which game the archive is from");
+                prompt_game(&header)
+            }
+            Err(err) => exit_with_error("Failed to detect the game", err),
+        }
+    };
+
+    let keys = match args.key {
+        Some(key) => CustomGame {
+            key,
+            ..CustomGame::from(game)
+        },
+        None => CustomGame::from(game),
+    };
+
+    // Detection rewinds the reader, the entry table comes right after the header
+    reader
+        .seek(io::SeekFrom::Start(NPA_HEADER_SIZE))
+        .unwrap_or_else(|err| exit_with_error("Failed to read the entry table", err));
+    let encoding = args.encoding.unwrap_or_default();
+
+    let table_progress = if args.stdout {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(header.total_count as u64)
+    }
+    .with_message("Reading entry table")
+    .with_style(
+        ProgressStyle::with_template("{msg} {wide_bar} {pos}/{len}")
+            .expect("progress template is valid"),
+    );
+
+    let entries = read_entries_with_progress(
         &mut reader,
         &header,
-        args.game == Game::LamentoTrail || args.game == Game::Lamento,
+        game.add_bytes_if_encrypted(),
+        args.name_encoding.unwrap_or_default(),
+        |read| table_progress.set_position(read as u64),
     )
-    .unwrap();
+    .unwrap_or_else(|err| exit_with_error("Failed to read the entry table", err));
 
-    let output_directory = args
-        .output_dir
-        .unwrap_or_else(|| PathBuf::from(args.file.file_stem().expect("input file has no stem")));
+    table_progress.finish_and_clear();
 
-    if !output_directory.exists() {
-        fs::create_dir(&output_directory).unwrap();
+    let lossy_names: Vec<_> = entries.iter().filter(|entry| entry.decode_lossy).collect();
+
+    if args.strict_names && !lossy_names.is_empty() {
+        for entry in &lossy_names {
+            eprintln!("Couldn't cleanly decode \"{}\"", entry.file_path.display());
+        }
+
+        eprintln!(
+            "{} names couldn't be decoded cleanly, the game or --name-encoding is probably wrong",
+            lossy_names.len()
+        );
+        std::process::exit(1);
     }
 
-    let progress_bar = ProgressBar::new(header.total_count as u64);
+    let archive = NpaArchive::from_parts(reader, header, entries).with_game(game);
 
-    for entry in entries {
-        let file_name = entry.file_path.to_string_lossy().to_string();
-        let path = output_directory.join(file_name);
+    let output_directory = args.output_dir.unwrap_or_else(|| match input.file_stem() {
+        Some(stem) => PathBuf::from(stem),
+        None => exit_with_error(
+            format!(
+                "Can't name an output directory after \"{}\"",
+                input.display()
+            ),
+            "pass one with --output",
+        ),
+    });
 
-        if entry.is_directory() {
-            fs::create_dir_all(path).unwrap();
+    if args.tui {
+        tui::run(archive, game, output_directory, encoding)
+            .unwrap_or_else(|err| exit_with_error("The terminal browser failed", err));
+        return;
+    }
+
+    // Extraction shouldn't touch the data unless asked to
+    let mut options = ExtractOptions::new()
+        .text_mode(if args.decode_text || args.encoding.is_some() {
+            TextMode::Decode(encoding)
+        } else {
+            TextMode::Raw
+        })
+        .reencode_images(args.reencode_images)
+        .quality(args.quality)
+        .auto_orient(!args.no_auto_orient)
+        .number_prefix(args.number_prefix)
+        .flatten(args.flatten)
+        .overwrite(if args.force {
+            Overwrite::Replace
         } else {
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent).expect("Failed to create parent directory");
+            Overwrite::Skip
+        })
+        .size_policy(if args.strict_sizes {
+            SizePolicy::Strict
+        } else {
+            SizePolicy::Lenient
+        })
+        .limit(args.limit)
+        .order(args.sort);
+
+    let filtering = !args.filter.is_empty() || !args.exclude.is_empty();
+    options.filter = (!args.filter.is_empty()).then(|| build_glob_set(&args.filter));
+    options.exclude = (!args.exclude.is_empty()).then(|| build_glob_set(&args.exclude));
+
+    let header = archive.header().clone();
+    let entries = archive.entries().to_vec();
+    let mut reader = archive.into_inner();
+
+    if let Some(path) = &args.entry_path {
+        let Some(entry) = find_entry(&entries, path).filter(|entry| !entry.is_directory()) else {
+            eprintln!("No file \"{}\" in the archive", path.display());
+            std::process::exit(1);
+        };
+
+        let mut out = io::stdout().lock();
+        read_entry_data_to_generic(
+            &mut reader,
+            &header,
+            entry,
+            &keys,
+            options.text_mode,
+            options.size_policy,
+            &mut out,
+        )
+        .and_then(|_| Ok(out.flush()?))
+        .unwrap_or_else(|err| {
+            exit_with_error(format!("Failed to read \"{}\"", path.display()), err)
+        });
+        return;
+    }
+
+    let selected = select_entries(&header, &entries, &options);
+
+    // Extracting reports entries with unsafe paths as failures, everything else leaves them out
+    let safe_selection = || -> Vec<(&NpaEntry, PathBuf)> {
+        selected
+            .iter()
+            .filter_map(|(entry, path)| match sanitize_path(path) {
+                Ok(path) => Some((*entry, path)),
+                Err(err) => {
+                    log::error!("Skipping entry: {err}");
+                    None
+                }
+            })
+            .collect()
+    };
+
+    if args.dry_run {
+        let archive_len = fs::metadata(&input)
+            .map(|metadata| metadata.len())
+            .unwrap_or_else(|err| exit_with_error("Failed to read the archive size", err));
+
+        if !dry_run(&header, &entries, &safe_selection(), archive_len, filtering) {
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    if args.manifest.is_some() || args.verify.is_some() {
+        let selected = safe_selection();
+        let progress_bar = ProgressBar::new(selected.len() as u64);
+        let (manifest, failures) = checksum_files(
+            &mut reader,
+            &header,
+            &selected,
+            &keys,
+            &options,
+            &progress_bar,
+        );
+        progress_bar.finish_and_clear();
+
+        for err in &failures {
+            eprintln!("{err}");
+        }
+
+        if let Some(path) = &args.manifest {
+            fs::File::create(path)
+                .map(io::BufWriter::new)
+                .and_then(|mut out| {
+                    write_manifest(&mut out, &manifest)?;
+                    out.flush()
+                })
+                .unwrap_or_else(|err| {
+                    exit_with_error(format!("Failed to write \"{}\"", path.display()), err)
+                });
+
+            println!("Wrote checksums of {} files", manifest.len());
+        }
+
+        if let Some(path) = &args.verify {
+            let expected = fs::File::open(path)
+                .map_err(NpaError::from)
+                .and_then(|file| read_manifest(io::BufReader::new(file)))
+                .unwrap_or_else(|err| {
+                    exit_with_error(format!("Failed to read \"{}\"", path.display()), err)
+                });
+
+            let mismatches = compare_manifests(&expected, &manifest);
+            for mismatch in &mismatches {
+                println!("{mismatch}");
             }
 
-            fs::write(
-                path,
-                read_entry_data(&mut reader, &header, &entry, args.game).unwrap(),
+            // Files that failed to read show up as missing
+            let differing = mismatches
+                .iter()
+                .filter(|mismatch| !matches!(mismatch, Mismatch::Missing(_)))
+                .count();
+            println!(
+                "{} of {} files match the manifest",
+                manifest.len() - differing,
+                expected.len()
+            );
+
+            if !mismatches.is_empty() {
+                std::process::exit(1);
+            }
+        }
+
+        if !failures.is_empty() {
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    if let Some(zip_path) = &args.zip {
+        if zip_path.exists() && !args.force {
+            eprintln!(
+                "\"{}\" already exists, pass --force to overwrite it",
+                zip_path.display()
+            );
+            std::process::exit(1);
+        }
+
+        let selected = safe_selection();
+        let progress_bar = ProgressBar::new(selected.len() as u64);
+        let report = write_zip(
+            &mut reader,
+            &header,
+            &selected,
+            zip_path,
+            &keys,
+            &options,
+            &progress_bar,
+        )
+        .unwrap_or_else(|err| {
+            exit_with_error(format!("Failed to write \"{}\"", zip_path.display()), err)
+        });
+
+        progress_bar.finish_and_clear();
+        log::info!(
+            "Wrote {} files ({} bytes) to \"{}\"",
+            report.files,
+            report.bytes,
+            zip_path.display()
+        );
+        return;
+    }
+
+    if !output_directory.exists() {
+        fs::create_dir(&output_directory)
+            .unwrap_or_else(|err| exit_with_error("Failed to create the output directory", err));
+    }
+
+    let files: Vec<&NpaEntry> = selected
+        .iter()
+        .map(|(entry, _)| *entry)
+        .filter(|entry| !entry.is_directory())
+        .collect();
+    let file_count = files.len();
+
+    // Sizes come from the entry table, so they describe the archive rather than what was written
+    let stored: u64 = files.iter().map(|entry| entry.compressed_size as u64).sum();
+    let original = if header.compressed {
+        total_original_size(files.iter().copied())
+    } else {
+        stored
+    };
+    let size = |entry: &NpaEntry| {
+        let size = if header.compressed {
+            entry.original_size
+        } else {
+            entry.compressed_size
+        };
+
+        size as u64
+    };
+
+    // Files can differ a lot in size, so progress is measured in bytes to keep the ETA useful
+    let progress_bar = ProgressBar::new(original).with_style(
+        ProgressStyle::with_template("{wide_bar} {bytes}/{total_bytes} ETA {eta}")
+            .expect("progress template is valid"),
+    );
+    let threads = if args.serial {
+        1
+    } else {
+        args.threads
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    };
+
+    // A corrupt entry shouldn't throw away everything that was extracted before it, so failures
+    // are collected and reported once the rest is done
+    let failures = Mutex::new(Vec::new());
+    let on_file = |entry: &NpaEntry, result: Result<ExtractReport, NpaError>| {
+        if let Err(err) = result {
+            failures.lock().expect("failure lock poisoned").push(err);
+        }
+
+        progress_bar.inc(size(entry));
+
+        Ok(())
+    };
+
+    let result = if threads == 1 || file_count < PARALLEL_THRESHOLD {
+        extract_all_with_progress(
+            &mut reader,
+            &header,
+            &entries,
+            &output_directory,
+            &keys,
+            &options,
+            on_file,
+        )
+    } else {
+        // A pool of its own, so that the global one doesn't decide how many threads are used
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap_or_else(|err| exit_with_error("Failed to start the extraction threads", err));
+
+        pool.install(|| {
+            extract_all_parallel_with_progress(
+                &input,
+                &header,
+                &entries,
+                &output_directory,
+                &keys,
+                &options,
+                on_file,
             )
-            .unwrap();
+        })
+    };
+
+    let report = result.unwrap_or_else(|err| exit_with_error("Failed to extract", err));
+    let failures = failures.into_inner().expect("failure lock poisoned");
+
+    progress_bar.finish_and_clear();
+    println!(
+        "Extracted {} files, {stored} bytes stored and {original} original ({})",
+        report.files,
+        ratio(stored, original)
+    );
+
+    if report.renamed > 0 {
+        println!(
+            "Renamed {} files whose names collided after flattening",
+            report.renamed
+        );
+    }
+    log::info!(
+        "Wrote {} bytes, {} files with text that couldn't be decoded cleanly",
+        report.bytes,
+        report.decode_warnings
+    );
+
+    if report.skipped > 0 {
+        eprintln!(
+            "Skipped {} files that already exist, pass --force to overwrite them",
+            report.skipped
+        );
+    }
+
+    if !failures.is_empty() {
+        for err in &failures {
+            eprintln!("Failed to extract {err}");
+        }
+
+        eprintln!("{} of {file_count} files failed to extract", failures.len());
+        std::process::exit(1);
+    }
+}
+
+/// Reads the header and entry table, for listing them without reading any of the entries' data
+///
+/// Without a game, the way names are encrypted is detected from the names themselves.
+fn read_table<R: io::Read + io::Seek>(
+    reader: &mut R,
+    game: Option<Game>,
+    auto: bool,
+    name_encoding: TextEncoding,
+) -> Result<(NpaHead, Vec<NpaEntry>, Option<Game>), NpaError> {
+    let game = match game {
+        Some(game) => Some(game),
+        None if auto => Some(detect_game(reader)?),
+        None => None,
+    };
+
+    let header = parse_head(reader)?;
+    let add_bytes = match game {
+        Some(game) => game.add_bytes_if_encrypted(),
+        None => detect_header_key_mode(reader, &header)?,
+    };
+
+    let entries = read_entries_with_progress(reader, &header, add_bytes, name_encoding, |_| {})?;
+
+    Ok((header, entries, game))
+}
+
+/// Prints the archive's summary and entries as JSON
+#[cfg(feature = "serde")]
+fn print_manifest(info: &ArchiveInfo, entries: &[NpaEntry]) -> Result<(), NpaError> {
+    #[derive(serde::Serialize)]
+    struct Manifest<'a> {
+        #[serde(flatten)]
+        info: &'a ArchiveInfo,
+        entries: &'a [NpaEntry],
+    }
+
+    let mut out = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut out, &Manifest { info, entries }).map_err(io::Error::from)?;
+
+    Ok(writeln!(out)?)
+}
+
+/// Prints a table of the entries with their sizes
+fn list_entries(header: &NpaHead, entries: &[NpaEntry]) -> Result<(), NpaError> {
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|entry| size_columns(header, entry))
+        .collect();
+
+    print_table(&["TYPE", "STORED", "ORIGINAL", "RATIO", "PATH"], rows)
+}
+
+/// How much of the data [`list_entries_verbose`] reads to find out its type
+const MIME_PREFIX_LEN: usize = 0x100;
+
+/// Same as [`list_entries`], with whether each name decoded cleanly, the MIME type [`infer`] finds
+/// at the start of the data and whether it knows the entry's extension
+///
+/// Without a game data can only be read from archives that aren't encrypted.
+fn list_entries_verbose<R: io::Read + io::Seek>(
+    reader: &mut R,
+    header: &NpaHead,
+    entries: &[NpaEntry],
+    game: Option<Game>,
+) -> Result<(), NpaError> {
+    // Unencrypted data reads the same with every game
+    let game = game.or((!header.encrypted).then_some(Game::ChaosHead));
+
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|entry| {
+            let mut row = size_columns(header, entry);
+            let path = row.pop().expect("the path is the last column");
+
+            let name = if entry.decode_lossy { "lossy" } else { "ok" };
+            let (mime, known) = if entry.is_directory() {
+                (String::new(), String::new())
+            } else {
+                let mime = match game {
+                    Some(game) => {
+                        match read_entry_prefix(reader, header, entry, &game, MIME_PREFIX_LEN) {
+                            Ok(prefix) => infer::get(&prefix)
+                                .map_or("unknown", |kind| kind.mime_type())
+                                .to_string(),
+                            Err(err) => {
+                                log::warn!("{}: {err}", entry.file_path.display());
+                                String::from("error")
+                            }
+                        }
+                    }
+                    None => String::from("-"),
+                };
+                let known = entry
+                    .extension()
+                    .is_some_and(|extension| infer::is_supported(&extension));
+
+                (mime, String::from(if known { "yes" } else { "no" }))
+            };
+
+            row.extend([name.to_string(), mime, known, path]);
+            row
+        })
+        .collect();
+
+    print_table(
+        &[
+            "TYPE", "STORED", "ORIGINAL", "RATIO", "NAME", "MIME", "KNOWN", "PATH",
+        ],
+        rows,
+    )
+}
+
+/// Type, sizes and path of `entry`, the columns of [`list_entries`]
+fn size_columns(header: &NpaHead, entry: &NpaEntry) -> Vec<String> {
+    if entry.is_directory() {
+        return vec![
+            String::from("dir"),
+            String::new(),
+            String::new(),
+            String::new(),
+            entry.file_path.display().to_string(),
+        ];
+    }
+
+    let original = if header.compressed {
+        entry.original_size
+    } else {
+        entry.compressed_size
+    };
+    vec![
+        String::from("file"),
+        entry.compressed_size.to_string(),
+        original.to_string(),
+        ratio(entry.compressed_size as u64, original as u64),
+        entry.file_path.display().to_string(),
+    ]
+}
+
+/// Prints `rows` under `titles`, with all but the last column padded to the same width and the
+/// columns of [`size_columns`] holding sizes aligned to the right
+fn print_table(titles: &[&str], rows: Vec<Vec<String>>) -> Result<(), NpaError> {
+    let widths: Vec<usize> = (0..titles.len() - 1)
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].len())
+                .chain([titles[column].len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let mut out = io::stdout().lock();
+    let titles = titles.iter().map(|title| title.to_string()).collect();
+
+    for row in std::iter::once(titles).chain(rows) {
+        let mut line = String::new();
+
+        for (column, (cell, &width)) in row.iter().zip(&widths).enumerate() {
+            if (1..=3).contains(&column) {
+                line.push_str(&format!("{cell:>width$}  "));
+            } else {
+                line.push_str(&format!("{cell:<width$}  "));
+            }
+        }
+
+        line.push_str(row.last().expect("rows have a path"));
+        writeln!(out, "{line}")?;
+    }
+
+    Ok(())
+}
+
+/// Stored size as a percentage of the original size
+fn ratio(stored: u64, original: u64) -> String {
+    match original {
+        0 => String::from("-"),
+        original => format!("{:.1}%", stored as f64 / original as f64 * 100.0),
+    }
+}
+
+/// Prints `err` after `context` and exits with a failure code, for errors that leave nothing to
+/// carry on with
+fn exit_with_error(context: impl std::fmt::Display, err: impl std::fmt::Display) -> ! {
+    eprintln!("{context}: {err}");
+    std::process::exit(1);
+}
+
+/// Prints what extracting `selected` would write, returning whether every selected entry is valid
+fn dry_run(
+    header: &NpaHead,
+    entries: &[NpaEntry],
+    selected: &[(&NpaEntry, PathBuf)],
+    archive_len: u64,
+    filtering: bool,
+) -> bool {
+    let mut directories = HashSet::new();
+    let mut files = 0;
+    let mut bytes = 0;
+    let mut lossy = 0;
+    let mut invalid = 0;
+
+    for (entry, path) in selected {
+        if entry.decode_lossy {
+            lossy += 1;
+        }
+
+        if entry.is_directory() {
+            directories.insert(path.clone());
+            continue;
+        }
+
+        directories.extend(
+            path.ancestors()
+                .skip(1)
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .map(Path::to_path_buf),
+        );
+
+        if let Err(err) = entry.validate(header, archive_len) {
+            eprintln!("{}: {err}", entry.file_path.display());
+            invalid += 1;
+        }
+
+        files += 1;
+        bytes += if header.compressed {
+            entry.original_size
+        } else {
+            entry.compressed_size
+        } as u64;
+    }
+
+    if filtering {
+        let total = file_count_actual(entries);
+        println!("{files} of {total} files match");
+    }
+
+    println!(
+        "Would write {files} files in {} directories, {bytes} bytes in total",
+        directories.len()
+    );
+
+    if lossy > 0 {
+        println!("{lossy} names couldn't be decoded cleanly");
+    }
+
+    if invalid > 0 {
+        println!("{invalid} entries are invalid and would fail to extract");
+    }
+
+    invalid == 0
+}
+
+/// Writes the selected entries into a new ZIP file at `path`, one at a time in table order
+fn write_zip<R: io::Read + io::Seek, G: GameKeys>(
+    reader: &mut R,
+    header: &NpaHead,
+    selected: &[(&NpaEntry, PathBuf)],
+    path: &Path,
+    game: &G,
+    options: &ExtractOptions,
+    progress_bar: &ProgressBar,
+) -> Result<ExtractReport, NpaError> {
+    let mut zip = ZipWriter::new(io::BufWriter::new(fs::File::create(path)?));
+    let file_options = SimpleFileOptions::default().large_file(true);
+    let mut report = ExtractReport::default();
+
+    for (entry, path) in selected {
+        let name = slash_path(path);
+
+        if entry.is_directory() {
+            zip.add_directory(name, file_options)
+                .map_err(io::Error::from)?;
+        } else {
+            zip.start_file(name, file_options)
+                .map_err(io::Error::from)?;
+            report.bytes += read_entry_data_to_generic(
+                reader,
+                header,
+                entry,
+                game,
+                options.text_mode,
+                options.size_policy,
+                &mut zip,
+            )?;
+            report.files += 1;
         }
 
         progress_bar.inc(1);
     }
+
+    zip.finish().map_err(io::Error::from)?.flush()?;
+
+    Ok(report)
+}
+
+/// Checksums the data of the selected files, collecting the entries that fail to read instead of
+/// stopping at the first one
+fn checksum_files<R: io::Read + io::Seek, G: GameKeys>(
+    reader: &mut R,
+    header: &NpaHead,
+    selected: &[(&NpaEntry, PathBuf)],
+    game: &G,
+    options: &ExtractOptions,
+    progress_bar: &ProgressBar,
+) -> (Vec<ManifestEntry>, Vec<NpaError>) {
+    let mut manifest = Vec::new();
+    let mut failures = Vec::new();
+
+    for (entry, path) in selected {
+        if !entry.is_directory() {
+            let checksum = ManifestEntry::compute(
+                reader,
+                header,
+                entry,
+                slash_path(path),
+                game,
+                options.text_mode,
+                options.size_policy,
+            );
+
+            match checksum {
+                Ok(checksum) => manifest.push(checksum),
+                Err(err) => failures.push(err.with_path(&entry.file_path)),
+            }
+        }
+
+        progress_bar.inc(1);
+    }
+
+    (manifest, failures)
+}
+
+/// Adds every file under `directory` to a new archive at `output`, in sorted order
+fn pack(directory: &Path, output: &Path, compress: bool) -> Result<(), NpaError> {
+    fn add_directory(writer: &mut NpaWriter, root: &Path, current: &Path) -> io::Result<()> {
+        let mut children = fs::read_dir(current)?
+            .map(|child| child.map(|child| child.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        children.sort();
+
+        for child in children {
+            if child.is_dir() {
+                add_directory(writer, root, &child)?;
+            } else {
+                let relative = child
+                    .strip_prefix(root)
+                    .expect("children are under the packed directory");
+
+                writer.add_file(relative, fs::read(&child)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut writer = NpaWriter::new().compressed(compress);
+    add_directory(&mut writer, directory, directory)?;
+
+    let mut out = io::BufWriter::new(fs::File::create(output)?);
+    writer.write(&mut out)?;
+
+    Ok(out.flush()?)
+}
+
+fn parse_glob(pattern: &str) -> Result<Glob, globset::Error> {
+    GlobBuilder::new(pattern).case_insensitive(true).build()
+}
+
+fn build_glob_set(globs: &[Glob]) -> GlobSet {
+    globs
+        .iter()
+        .fold(GlobSetBuilder::new(), |mut builder, glob| {
+            builder.add(glob.clone());
+            builder
+        })
+        .build()
+        .expect("globs were already validated while parsing")
+}
+
+/// `path` with `/` separators on every platform, so that globs match the same everywhere
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Reads a substitution table for `--key`, which has to be exactly 256 bytes long
+fn parse_key(path: &str) -> Result<[u8; 256], String> {
+    let bytes = fs::read(path).map_err(|err| format!("failed to read \"{path}\": {err}"))?;
+    let length = bytes.len();
+
+    bytes
+        .try_into()
+        .map_err(|_| format!("\"{path}\" is {length} bytes long, a key table has 256"))
+}
+
+fn parse_encoding(label: &str) -> Result<TextEncoding, String> {
+    TextEncoding::for_label(label).ok_or_else(|| format!("unknown encoding \"{label}\""))
+}
+
+/// Asks the user which of the games that could have written the archive with `header` it's from,
+/// most likely first, or exits if there is no terminal to ask on
+///
+/// If only one game is a candidate, it's returned without asking.
+fn prompt_game(header: &NpaHead) -> Game {
+    let candidates = Game::candidates(header);
+
+    if let [game] = candidates[..] {
+        return game;
+    }
+
+    let names: Vec<String> = candidates
+        .iter()
+        .filter_map(|game| game.to_possible_value())
+        .map(|value| value.get_name().to_string())
+        .collect();
+
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                format!(
+                    "--game is required when not running interactively, candidates are: {}",
+                    names.join(", ")
+                ),
+            )
+            .exit();
+    }
+
+    let titles: Vec<&str> = candidates.iter().map(|game| game.title()).collect();
+    let selection = dialoguer::Select::new()
+        .with_prompt("Which game is this archive from?")
+        .items(&titles)
+        .default(0)
+        .interact()
+        .unwrap_or_else(|err| exit_with_error("Failed to read the game selection", err));
+
+    candidates[selection]
+}
+
+fn dump_key(game: Game) {
+    for (row, chunk) in game.encryption_key().chunks(16).enumerate() {
+        let bytes: Vec<String> = chunk.iter().map(|b| format!("{b:02X}")).collect();
+
+        println!("{:02X}: {}", row * 16, bytes.join(" "));
+    }
 }
@@ -0,0 +1,272 @@
+//! Checksums of extracted data, for telling whether two extractions produced the same bytes
+//!
+//! A manifest is a CSV file with a `path,file_id,crc,size` line per file, where `crc` is the
+//! CRC-32 of the data as extraction would write it, in hex.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{GameKeys, NpaEntry, NpaError, NpaHead, SizePolicy, TextMode, write_entry_data};
+
+const MANIFEST_HEADER: &str = "path,file_id,crc,size";
+
+/// Checksum of the data of a single file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path the file is extracted to, with `/` as the separator
+    pub path: String,
+
+    pub file_id: u32,
+
+    /// CRC-32 of the extracted data
+    pub crc: u32,
+
+    /// Number of bytes extraction writes
+    pub size: u64,
+}
+
+impl ManifestEntry {
+    /// Reads the data of `entry` the same way extracting it with `text_mode` and `size_policy`
+    /// would and checksums it, without writing anything
+    pub fn compute<R: Read + Seek, G: GameKeys>(
+        reader: &mut R,
+        header: &NpaHead,
+        entry: &NpaEntry,
+        path: impl Into<String>,
+        game: G,
+        text_mode: TextMode,
+        size_policy: SizePolicy,
+    ) -> Result<Self, NpaError> {
+        let archive_len = reader.seek(SeekFrom::End(0))?;
+        let mut out = ChecksumWriter::default();
+        let (size, _) = write_entry_data(
+            reader,
+            header,
+            entry,
+            &game,
+            text_mode,
+            size_policy,
+            archive_len,
+            &mut out,
+        )?;
+
+        Ok(ManifestEntry {
+            path: path.into(),
+            file_id: entry.file_id,
+            crc: out.hasher.finalize(),
+            size,
+        })
+    }
+}
+
+#[derive(Default)]
+struct ChecksumWriter {
+    hasher: crc32fast::Hasher,
+}
+
+impl Write for ChecksumWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes `entries` as a manifest, starting with a header line
+pub fn write_manifest<W: Write>(out: &mut W, entries: &[ManifestEntry]) -> io::Result<()> {
+    writeln!(out, "{MANIFEST_HEADER}")?;
+
+    for entry in entries {
+        writeln!(
+            out,
+            "{},{},{:08x},{}",
+            entry.path, entry.file_id, entry.crc, entry.size
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Parses a manifest written by [`write_manifest`]
+///
+/// Fields are split off from the end of each line, so paths may contain commas.
+pub fn read_manifest<R: BufRead>(input: R) -> Result<Vec<ManifestEntry>, NpaError> {
+    let mut entries = Vec::new();
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+
+        if line.is_empty() || index == 0 && line == MANIFEST_HEADER {
+            continue;
+        }
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} isn't a valid manifest line: {line:?}", index + 1),
+            )
+        };
+
+        let mut fields = line.rsplitn(4, ',');
+        let (Some(size), Some(crc), Some(file_id), Some(path)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid().into());
+        };
+
+        entries.push(ManifestEntry {
+            path: path.to_string(),
+            file_id: file_id.parse().map_err(|_| invalid())?,
+            crc: u32::from_str_radix(crc, 16).map_err(|_| invalid())?,
+            size: size.parse().map_err(|_| invalid())?,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// A difference between two manifests, see [`compare_manifests`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The file was extracted both times, but to different data
+    Changed {
+        expected: ManifestEntry,
+        actual: ManifestEntry,
+    },
+
+    /// The file is only in the expected manifest
+    Missing(ManifestEntry),
+
+    /// The file is only in the actual manifest
+    Unexpected(ManifestEntry),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Changed { expected, actual } => write!(
+                f,
+                "{}: expected CRC {:08x} and {} bytes, got {:08x} and {} bytes",
+                expected.path, expected.crc, expected.size, actual.crc, actual.size
+            ),
+            Mismatch::Missing(entry) => write!(f, "{}: missing", entry.path),
+            Mismatch::Unexpected(entry) => write!(f, "{}: not in the manifest", entry.path),
+        }
+    }
+}
+
+/// Lists the files whose data differs between `expected` and `actual`, matched by path
+///
+/// Changed and missing files come in the order of `expected`, followed by the files only in
+/// `actual`.
+pub fn compare_manifests(expected: &[ManifestEntry], actual: &[ManifestEntry]) -> Vec<Mismatch> {
+    let mut remaining: HashMap<&str, &ManifestEntry> = actual
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+
+    let mut mismatches: Vec<Mismatch> = expected
+        .iter()
+        .filter_map(|expected| match remaining.remove(expected.path.as_str()) {
+            None => Some(Mismatch::Missing(expected.clone())),
+            Some(actual) if (actual.crc, actual.size) != (expected.crc, expected.size) => {
+                Some(Mismatch::Changed {
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                })
+            }
+            Some(_) => None,
+        })
+        .collect();
+
+    mismatches.extend(
+        actual
+            .iter()
+            .filter(|entry| remaining.contains_key(entry.path.as_str()))
+            .map(|entry| Mismatch::Unexpected(entry.clone())),
+    );
+
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::Game;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let archive = crate::tests::build_archive(&[("a.txt", b"first"), ("b,c.dat", b"2")]);
+        let mut reader = Cursor::new(archive);
+        let header = crate::parse_head(&mut reader).unwrap();
+        let entries = crate::read_entries(&mut reader, &header, false).unwrap();
+
+        let manifest: Vec<ManifestEntry> = entries
+            .iter()
+            .map(|entry| {
+                let path = entry.file_path.to_string_lossy().into_owned();
+                ManifestEntry::compute(
+                    &mut reader,
+                    &header,
+                    entry,
+                    path,
+                    Game::ChaosHead,
+                    TextMode::Raw,
+                    SizePolicy::Lenient,
+                )
+                .unwrap()
+            })
+            .collect();
+
+        assert_eq!(manifest[0].crc, crc32fast::hash(b"first"));
+        assert_eq!(manifest[0].size, 5);
+        assert_eq!(manifest[1].path, "b,c.dat");
+
+        let mut csv = Vec::new();
+        write_manifest(&mut csv, &manifest).unwrap();
+
+        let parsed = read_manifest(Cursor::new(csv)).unwrap();
+        assert_eq!(parsed, manifest);
+        assert!(compare_manifests(&manifest, &parsed).is_empty());
+    }
+
+    #[test]
+    fn test_compare_manifests() {
+        let entry = |path: &str, crc| ManifestEntry {
+            path: path.to_string(),
+            file_id: 0,
+            crc,
+            size: 1,
+        };
+
+        let expected = [entry("a", 1), entry("b", 2), entry("c", 3)];
+        let actual = [entry("d", 4), entry("c", 3), entry("a", 5)];
+
+        assert_eq!(
+            compare_manifests(&expected, &actual),
+            [
+                Mismatch::Changed {
+                    expected: entry("a", 1),
+                    actual: entry("a", 5),
+                },
+                Mismatch::Missing(entry("b", 2)),
+                Mismatch::Unexpected(entry("d", 4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_manifest_rejects_bad_lines() {
+        let err = read_manifest(Cursor::new("path,file_id,crc,size\na,0,zz,1\n")).unwrap_err();
+
+        assert!(err.to_string().contains("line 2"), "{err}");
+    }
+}
@@ -0,0 +1,170 @@
+//! The entries of an archive as a nested directory tree
+
+use std::{collections::HashMap, ffi::OsString};
+
+use crate::NpaEntry;
+
+/// A file or directory in the tree built by [`build_tree`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct NpaNode {
+    /// Last component of the node's path, empty for the root
+    pub name: OsString,
+
+    /// Index of the node's entry, `None` for the root and for directories that only exist as the
+    /// parents of other entries
+    pub entry: Option<usize>,
+
+    /// Whether the node is a directory, even if it has no children
+    pub directory: bool,
+
+    /// Files and directories inside this one, in the order they first appear in the entry table
+    pub children: Vec<NpaNode>,
+}
+
+impl NpaNode {
+    /// Calls `f` with every node below this one and its depth, parents before their children,
+    /// starting at zero for the children of this node
+    pub fn walk<'a>(&'a self, f: &mut impl FnMut(&'a NpaNode, usize)) {
+        self.walk_from(0, f);
+    }
+
+    fn walk_from<'a>(&'a self, depth: usize, f: &mut impl FnMut(&'a NpaNode, usize)) {
+        for child in &self.children {
+            f(child, depth);
+            child.walk_from(depth + 1, f);
+        }
+    }
+}
+
+/// Builds the directory tree of `entries`, returning its root
+///
+/// Every ancestor directory shows up once, whether or not the archive has an entry for it and
+/// however its descendants are spread through the entry table. Entries with an empty path are left
+/// out.
+pub fn build_tree(entries: &[NpaEntry]) -> NpaNode {
+    /// A node whose children are still indices into the arena, so that they can be looked up by
+    /// name while the tree is built
+    #[derive(Default)]
+    struct Pending {
+        name: OsString,
+        entry: Option<usize>,
+        directory: bool,
+        children: Vec<usize>,
+        by_name: HashMap<OsString, usize>,
+    }
+
+    fn finish(arena: &mut [Pending], index: usize) -> NpaNode {
+        let pending = std::mem::take(&mut arena[index]);
+
+        NpaNode {
+            name: pending.name,
+            entry: pending.entry,
+            directory: pending.directory,
+            children: pending
+                .children
+                .into_iter()
+                .map(|child| finish(arena, child))
+                .collect(),
+        }
+    }
+
+    let mut arena = vec![Pending {
+        directory: true,
+        ..Default::default()
+    }];
+
+    for (index, entry) in entries.iter().enumerate() {
+        let components: Vec<OsString> = entry
+            .file_path
+            .components()
+            .map(|c| c.as_os_str().to_os_string())
+            .collect();
+
+        let Some((name, parents)) = components.split_last() else {
+            continue;
+        };
+
+        let mut parent = 0;
+
+        for (depth, component) in parents.iter().chain([name]).enumerate() {
+            let is_last = depth == parents.len();
+            let directory = !is_last || entry.is_directory();
+
+            // Only directories are looked up by name, so a file and a directory with the same
+            // name, or two files, stay separate nodes
+            let existing = arena[parent]
+                .by_name
+                .get(component)
+                .copied()
+                .filter(|_| directory);
+
+            parent = match existing {
+                Some(child) => child,
+                None => {
+                    let child = arena.len();
+                    arena.push(Pending {
+                        name: component.clone(),
+                        directory,
+                        ..Default::default()
+                    });
+
+                    arena[parent].children.push(child);
+
+                    if directory {
+                        arena[parent].by_name.insert(component.clone(), child);
+                    }
+
+                    child
+                }
+            };
+        }
+
+        arena[parent].entry.get_or_insert(index);
+    }
+
+    finish(&mut arena, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn entry(path: &str, type_: u8) -> NpaEntry {
+        NpaEntry {
+            file_path: PathBuf::from(path),
+            type_,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_build_tree() {
+        let entries = [
+            entry("a/x.png", 0),
+            entry("e.png", 0),
+            entry("a", 1),
+            entry("a/b/y.png", 0),
+            entry("e.png/z.png", 0),
+        ];
+
+        let mut nodes = Vec::new();
+        build_tree(&entries).walk(&mut |node, depth| {
+            nodes.push((depth, node.name.to_string_lossy().into_owned(), node.entry))
+        });
+
+        assert_eq!(
+            nodes,
+            [
+                (0, String::from("a"), Some(2)),
+                (1, String::from("x.png"), Some(0)),
+                (1, String::from("b"), None),
+                (2, String::from("y.png"), Some(3)),
+                (0, String::from("e.png"), Some(1)),
+                (0, String::from("e.png"), None),
+                (1, String::from("z.png"), Some(4)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,971 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Cursor, Read, Seek},
+    path::PathBuf,
+    process::Stdio,
+};
+
+use cat_nipa::{
+    ExtractOptions, ExtractReport, Game, ImageFormat, NpaArchive, NpaEntry, NpaError, NpaNode,
+    TextEncoding, TextMode, build_tree, output_paths, sanitize_path,
+};
+use encoding_rs::Encoding;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+};
+use strum::IntoEnumIterator;
+
+/// A single line in the entry tree
+struct Row {
+    /// Nesting level of the row, used for indentation
+    depth: usize,
+
+    /// Name of the last path component
+    label: String,
+
+    /// Index into [`Tui::entries`], `None` for directories that only exist implicitly
+    entry: Option<usize>,
+}
+
+enum Preview {
+    /// An entry that's probably text, with its raw bytes kept around to decode them again when the
+    /// encoding is changed
+    Text {
+        bytes: Vec<u8>,
+        text: String,
+        encoding: &'static Encoding,
+        lossy: bool,
+    },
+    Hex(Vec<u8>),
+    Error(String),
+}
+
+/// What the detail panel shows about the previewed entry beyond its table fields, worked out once
+/// when the preview is loaded
+#[derive(Default)]
+struct Details {
+    mime: Option<&'static str>,
+
+    /// Pixel dimensions, read from the image header without decoding the whole image
+    dimensions: Option<(u32, u32)>,
+
+    /// How many bytes the entry was read as, if that's not the size in the entry table
+    size_mismatch: Option<u64>,
+}
+
+impl Details {
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let dimensions = image::ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+
+        Details {
+            mime: infer::get(bytes).map(|kind| kind.mime_type()),
+            dimensions,
+            size_mismatch: None,
+        }
+    }
+}
+
+/// Order of the files within each directory of the tree
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+enum SortOrder {
+    /// By name, with directories mixed in
+    #[default]
+    Path,
+
+    /// Largest original size first
+    OriginalSize,
+
+    /// Largest stored size first
+    StoredSize,
+
+    /// By extension, then by name
+    Type,
+}
+
+impl SortOrder {
+    const ALL: [SortOrder; 4] = [
+        SortOrder::Path,
+        SortOrder::OriginalSize,
+        SortOrder::StoredSize,
+        SortOrder::Type,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            SortOrder::Path => "path",
+            SortOrder::OriginalSize => "original size",
+            SortOrder::StoredSize => "stored size",
+            SortOrder::Type => "type",
+        }
+    }
+
+    /// Sorts the children of `node` and everything below it. Other than by path, directories come
+    /// first in name order
+    fn sort(self, node: &mut NpaNode, entries: &[NpaEntry]) {
+        for child in &mut node.children {
+            self.sort(child, entries);
+        }
+
+        if self == SortOrder::Path {
+            return;
+        }
+
+        let file = |node: &NpaNode| node.entry.filter(|_| !node.directory).map(|i| &entries[i]);
+
+        // Stable, so that equal keys keep the path order
+        node.children.sort_by(|a, b| match (file(a), file(b)) {
+            (Some(a), Some(b)) => match self {
+                SortOrder::Path => std::cmp::Ordering::Equal,
+                SortOrder::OriginalSize => b.original_size.cmp(&a.original_size),
+                SortOrder::StoredSize => b.compressed_size.cmp(&a.compressed_size),
+                SortOrder::Type => a.extension().cmp(&b.extension()),
+            },
+            (None, Some(_)) => std::cmp::Ordering::Less,
+            (Some(_), None) => std::cmp::Ordering::Greater,
+            (None, None) => a.name.cmp(&b.name),
+        });
+    }
+}
+
+/// Encodings the text preview cycles through
+const ENCODINGS: [TextEncoding; 4] = [
+    TextEncoding::Detect,
+    TextEncoding::Forced(encoding_rs::SHIFT_JIS),
+    TextEncoding::Forced(encoding_rs::UTF_8),
+    TextEncoding::Forced(encoding_rs::EUC_JP),
+];
+
+struct Tui<R> {
+    archive: NpaArchive<R>,
+
+    /// Entries of the archive sorted by path
+    entries: Vec<NpaEntry>,
+
+    /// Where each of [`Tui::entries`] is extracted to inside the output directory, the same paths
+    /// extracting everything uses
+    paths: Vec<PathBuf>,
+    game: Game,
+    output_directory: PathBuf,
+    rows: Vec<Row>,
+    list_state: ListState,
+    marked: HashSet<usize>,
+    preview: Option<(usize, Preview)>,
+    details: Details,
+    preview_scroll: u16,
+    force_hex: bool,
+    encoding: TextEncoding,
+    wrap: bool,
+
+    /// Only entries whose path contains this are shown in the tree
+    filter: String,
+    sort: SortOrder,
+    editing_filter: bool,
+    status: String,
+
+    /// Where entries are written to be opened with another program, created on first use and
+    /// removed when the browser exits
+    open_directory: Option<PathBuf>,
+}
+
+pub fn run<R: Read + Seek>(
+    archive: NpaArchive<R>,
+    game: Game,
+    output_directory: PathBuf,
+    encoding: TextEncoding,
+) -> std::io::Result<()> {
+    // Collisions are renamed in table order, so the paths have to be worked out before sorting
+    let mut entries: Vec<_> = archive
+        .entries()
+        .iter()
+        .cloned()
+        .zip(output_paths(archive.entries()))
+        .collect();
+    entries.sort_by(|(a, _), (b, _)| a.file_path.cmp(&b.file_path));
+    let (entries, paths): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+
+    let rows = build_rows(&entries, "", SortOrder::default());
+    let mut app = Tui {
+        archive: archive.with_encoding(encoding),
+        entries,
+        paths,
+        game,
+        output_directory,
+        rows,
+        list_state: ListState::default().with_selected(Some(0)),
+        marked: HashSet::new(),
+        preview: None,
+        details: Details::default(),
+        preview_scroll: 0,
+        force_hex: false,
+        encoding,
+        wrap: false,
+        filter: String::new(),
+        sort: SortOrder::default(),
+        editing_filter: false,
+        status: String::from(
+            "↑/↓ navigate  ←/→ previous/next file  space mark  e extract  E extract all  p save as PNG  o open externally  / filter  s sort  h hex  c encoding  g game  w wrap  PgUp/PgDn scroll  q quit",
+        ),
+        open_directory: None,
+    };
+
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+
+    if let Some(directory) = &app.open_directory
+        && let Err(err) = fs::remove_dir_all(directory)
+    {
+        log::warn!("Failed to remove \"{}\": {err}", directory.display());
+    }
+
+    result
+}
+
+/// Flattens the directory tree of the entries into indented rows in `sort` order, see
+/// [`build_tree`]
+///
+/// With a non-empty `filter`, only files whose path contains it (ignoring case, with `/` as the
+/// separator) are kept, together with their ancestor directories.
+fn build_rows(entries: &[NpaEntry], filter: &str, sort: SortOrder) -> Vec<Row> {
+    fn push_rows(
+        node: &NpaNode,
+        depth: usize,
+        entries: &[NpaEntry],
+        filter: &str,
+        rows: &mut Vec<Row>,
+    ) {
+        for child in &node.children {
+            let row = Row {
+                depth,
+                label: child.name.to_string_lossy().to_string(),
+                entry: child.entry,
+            };
+
+            if child.directory {
+                let start = rows.len();
+                rows.push(row);
+                push_rows(child, depth + 1, entries, filter, rows);
+
+                if !filter.is_empty() && rows.len() == start + 1 {
+                    rows.pop();
+                }
+            } else if filter.is_empty()
+                || child
+                    .entry
+                    .is_some_and(|index| matches_filter(&entries[index], filter))
+            {
+                rows.push(row);
+            }
+        }
+    }
+
+    let mut tree = build_tree(entries);
+    sort.sort(&mut tree, entries);
+
+    let mut rows = Vec::with_capacity(entries.len());
+    push_rows(&tree, 0, entries, &filter.to_lowercase(), &mut rows);
+
+    rows
+}
+
+fn matches_filter(entry: &NpaEntry, filter: &str) -> bool {
+    let path: Vec<_> = entry
+        .file_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_lowercase())
+        .collect();
+
+    path.join("/").contains(filter)
+}
+
+impl<R: Read + Seek> Tui<R> {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> std::io::Result<()> {
+        loop {
+            self.load_preview();
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            if self.editing_filter {
+                match key.code {
+                    KeyCode::Enter | KeyCode::Esc => self.editing_filter = false,
+                    KeyCode::Backspace => {
+                        self.filter.pop();
+                        self.apply_filter();
+                    }
+                    KeyCode::Char(c) => {
+                        self.filter.push(c);
+                        self.apply_filter();
+                    }
+                    _ => {}
+                }
+
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => self.list_state.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.list_state.select_previous(),
+                KeyCode::Right => self.select_file(true),
+                KeyCode::Left => self.select_file(false),
+                KeyCode::Home => self.list_state.select_first(),
+                KeyCode::End => self.list_state.select_last(),
+                KeyCode::PageDown => self.preview_scroll = self.preview_scroll.saturating_add(16),
+                KeyCode::PageUp => self.preview_scroll = self.preview_scroll.saturating_sub(16),
+                KeyCode::Char('h') => self.force_hex = !self.force_hex,
+                KeyCode::Char('c') => self.cycle_encoding(),
+                KeyCode::Char('g') => self.cycle_game(),
+                KeyCode::Char('w') => self.wrap = !self.wrap,
+                KeyCode::Char('/') => self.editing_filter = true,
+                KeyCode::Char('s') => self.cycle_sort(),
+                KeyCode::Char(' ') => self.toggle_mark(),
+                KeyCode::Char('e') => self.extract(),
+                KeyCode::Char('E') => self.extract_all(),
+                KeyCode::Char('p') => self.save_png(),
+                KeyCode::Char('o') => self.open_externally(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Rebuilds the tree after the filter changed, the terminal only redraws after a key press so
+    /// this runs once per typed character
+    fn apply_filter(&mut self) {
+        self.rows = build_rows(&self.entries, &self.filter, self.sort);
+        self.list_state.select(Some(0));
+    }
+
+    /// Moves the selection to the next or previous file, skipping directories and staying put
+    /// when there is none left in that direction
+    fn select_file(&mut self, forward: bool) {
+        let current = self.list_state.selected().unwrap_or(0);
+        let is_file = |row: &Row| {
+            row.entry
+                .is_some_and(|index| !self.entries[index].is_directory())
+        };
+
+        let found = if forward {
+            self.rows
+                .iter()
+                .enumerate()
+                .skip(current + 1)
+                .find(|(_, row)| is_file(row))
+        } else {
+            self.rows
+                .iter()
+                .enumerate()
+                .take(current)
+                .rfind(|(_, row)| is_file(row))
+        };
+
+        if let Some((row, _)) = found {
+            self.list_state.select(Some(row));
+        }
+    }
+
+    /// Files in the rows below the selected directory, as far as the filter shows them
+    fn selected_folder(&self) -> Vec<usize> {
+        let Some(selected) = self.list_state.selected() else {
+            return Vec::new();
+        };
+        let Some(depth) = self.rows.get(selected).map(|row| row.depth) else {
+            return Vec::new();
+        };
+
+        self.rows[selected + 1..]
+            .iter()
+            .take_while(|row| row.depth > depth)
+            .filter_map(|row| row.entry)
+            .filter(|&index| !self.entries[index].is_directory())
+            .collect()
+    }
+
+    fn selected_entry(&self) -> Option<usize> {
+        self.list_state
+            .selected()
+            .and_then(|row| self.rows.get(row))
+            .and_then(|row| row.entry)
+            .filter(|&index| !self.entries[index].is_directory())
+    }
+
+    fn load_preview(&mut self) {
+        let Some(index) = self.selected_entry() else {
+            self.preview = None;
+            return;
+        };
+
+        if self.preview.as_ref().is_some_and(|(i, _)| *i == index) {
+            return;
+        }
+
+        let entry = &self.entries[index];
+        let known_binary = entry
+            .extension()
+            .is_some_and(|extension| infer::is_supported(&extension));
+        let expected_size = if self.archive.header().compressed {
+            entry.original_size
+        } else {
+            entry.compressed_size
+        } as u64;
+
+        self.preview_scroll = 0;
+        self.details = Details::default();
+        self.preview = Some((
+            index,
+            match self.archive.read_raw(entry, self.game) {
+                Ok(bytes) => {
+                    self.details = Details {
+                        size_mismatch: Some(bytes.len() as u64)
+                            .filter(|&read| read != expected_size),
+                        ..Details::from_bytes(&bytes)
+                    };
+
+                    if known_binary {
+                        Preview::Hex(bytes)
+                    } else {
+                        decode_preview(bytes, self.encoding)
+                    }
+                }
+                Err(err) => Preview::Error(err.to_string()),
+            },
+        ));
+    }
+
+    /// Switches to the next encoding, decoding the current preview again and using it for
+    /// extracted text entries too
+    fn cycle_encoding(&mut self) {
+        let current = ENCODINGS.iter().position(|&e| e == self.encoding);
+        self.encoding = ENCODINGS[current.map_or(0, |i| (i + 1) % ENCODINGS.len())];
+        self.archive.set_text_mode(TextMode::Decode(self.encoding));
+
+        if let Some((_, preview)) = &mut self.preview
+            && let Preview::Text { bytes, .. } = preview
+        {
+            *preview = decode_preview(std::mem::take(bytes), self.encoding);
+        }
+    }
+
+    /// Switches the data key to the next game, for when the archive was opened with the wrong one.
+    /// The names are already decoded, so only the preview is read again.
+    fn cycle_game(&mut self) {
+        let current = Game::iter().position(|game| game == self.game);
+        self.game = Game::iter()
+            .cycle()
+            .nth(current.map_or(0, |i| i + 1))
+            .unwrap_or(self.game);
+
+        self.preview = None;
+        self.status = format!("Reading entries as {}", self.game);
+    }
+
+    /// Switches to the next sort order, keeping the selected entry selected if it's still shown
+    fn cycle_sort(&mut self) {
+        let current = SortOrder::ALL.iter().position(|&sort| sort == self.sort);
+        self.sort = SortOrder::ALL[current.map_or(0, |i| (i + 1) % SortOrder::ALL.len())];
+
+        let selected = self
+            .list_state
+            .selected()
+            .and_then(|row| self.rows.get(row))
+            .and_then(|row| row.entry);
+
+        self.rows = build_rows(&self.entries, &self.filter, self.sort);
+        self.list_state.select(Some(
+            selected
+                .and_then(|entry| self.rows.iter().position(|row| row.entry == Some(entry)))
+                .unwrap_or(0),
+        ));
+        self.status = format!("Sorted by {}", self.sort.label());
+    }
+
+    fn toggle_mark(&mut self) {
+        if let Some(index) = self.selected_entry()
+            && !self.marked.remove(&index)
+        {
+            self.marked.insert(index);
+        }
+
+        self.list_state.select_next();
+    }
+
+    fn extract_options(&self) -> ExtractOptions {
+        ExtractOptions::new().text_mode(TextMode::Decode(self.encoding))
+    }
+
+    /// Extracts the marked files, or the selected one, or every file below the selected directory
+    fn extract(&mut self) {
+        let mut targets: Vec<usize> = if self.marked.is_empty() {
+            self.selected_entry()
+                .map(|index| vec![index])
+                .unwrap_or_else(|| self.selected_folder())
+        } else {
+            self.marked.iter().copied().collect()
+        };
+
+        targets.sort_unstable();
+
+        let options = self.extract_options();
+        let mut report = ExtractReport::default();
+
+        // One entry failing shouldn't throw away the others, or leave them marked to be extracted
+        // again
+        let mut failures = Vec::new();
+
+        for index in targets {
+            let entry = &self.entries[index];
+            let result = self.archive.extract_entry(
+                entry,
+                &self.output_directory,
+                &self.paths[index],
+                self.game,
+                &options,
+            );
+
+            match result {
+                Ok(written) => report += written,
+                Err(err) => failures.push(err),
+            }
+        }
+
+        self.marked.clear();
+        self.report_extraction(report);
+
+        if let Some(first) = failures.first() {
+            self.status
+                .push_str(&format!(", {} failed: {first}", failures.len()));
+        }
+    }
+
+    /// Extracts every entry, blocking until it's done as the interface only redraws between keys
+    fn extract_all(&mut self) {
+        let options = self.extract_options();
+
+        match self
+            .archive
+            .extract_all(&self.output_directory, self.game, &options)
+        {
+            Ok(report) => self.report_extraction(report),
+            Err(err) => self.status = format!("Failed to extract {err}"),
+        }
+    }
+
+    /// Extracts the previewed entry re-encoded as PNG, next to where `e` would have put it
+    fn save_png(&mut self) {
+        let Some((index, _)) = self.preview else {
+            return;
+        };
+
+        if self.details.dimensions.is_none() {
+            self.status = String::from("Only image entries can be saved as PNG");
+            return;
+        }
+
+        let entry = &self.entries[index];
+        let options = self
+            .extract_options()
+            .reencode_images(Some(ImageFormat::Png));
+
+        let path = &self.paths[index];
+
+        match self
+            .archive
+            .extract_entry(entry, &self.output_directory, path, self.game, &options)
+        {
+            Ok(_) => {
+                self.status = format!(
+                    "Saved \"{}\"",
+                    self.output_directory
+                        .join(path.with_extension(ImageFormat::Png.extension()))
+                        .display()
+                )
+            }
+            Err(err) => self.status = format!("Failed to save {err}"),
+        }
+    }
+
+    /// Writes the previewed entry to a temporary file and opens it with the system's default
+    /// program, for videos and other media the terminal can't show
+    fn open_externally(&mut self) {
+        let Some((index, _)) = self.preview else {
+            return;
+        };
+
+        let entry = self.entries[index].clone();
+
+        // The whole path is kept, so that files with the same name in different directories
+        // don't overwrite each other
+        let written = self.session_directory().and_then(|directory| {
+            let path = directory.join(sanitize_path(&entry.file_path)?);
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let data = self.archive.read_raw(&entry, self.game)?;
+            fs::write(&path, data)?;
+
+            Ok(path)
+        });
+
+        let path = match written {
+            Ok(path) => path,
+            Err(err) => {
+                self.status = format!("Failed to write \"{}\": {err}", entry.file_path.display());
+                return;
+            }
+        };
+
+        let opener = if cfg!(target_os = "macos") {
+            "open"
+        } else if cfg!(windows) {
+            "explorer"
+        } else {
+            "xdg-open"
+        };
+
+        self.status = match std::process::Command::new(opener)
+            .arg(&path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(_) => format!("Opened \"{}\"", path.display()),
+            Err(err) => format!("Failed to run {opener}: {err}"),
+        };
+    }
+
+    /// Creates the directory for [`Tui::open_externally`] the first time it's needed, with a name
+    /// no other session uses
+    fn session_directory(&mut self) -> Result<PathBuf, NpaError> {
+        if let Some(directory) = &self.open_directory {
+            return Ok(directory.clone());
+        }
+
+        let base = std::env::temp_dir();
+        let id = std::process::id();
+
+        for attempt in 0.. {
+            let directory = base.join(format!("cat-nipa-{id}-{attempt}"));
+
+            match fs::create_dir(&directory) {
+                Ok(()) => {
+                    self.open_directory = Some(directory.clone());
+                    return Ok(directory);
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        unreachable!("there's always another attempt")
+    }
+
+    fn report_extraction(&mut self, report: ExtractReport) {
+        self.status = format!(
+            "Extracted {} file(s), {} bytes, to \"{}\"",
+            report.files,
+            report.bytes,
+            self.output_directory.display()
+        );
+
+        if report.skipped > 0 {
+            self.status
+                .push_str(&format!(", skipped {} that already exist", report.skipped));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let [tree, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(main);
+        let [details, preview] =
+            Layout::vertical([Constraint::Length(DETAIL_LINES + 2), Constraint::Min(1)])
+                .areas(right);
+
+        let items = self.rows.iter().map(|row| {
+            let marker = match row.entry {
+                Some(index) if self.marked.contains(&index) => "* ",
+                _ => "  ",
+            };
+
+            let suffix = match row.entry {
+                Some(index) if !self.entries[index].is_directory() => "",
+                _ => "/",
+            };
+
+            let warning = match row.entry {
+                Some(index) if self.entries[index].decode_lossy => "  (name not cleanly decoded)",
+                _ => "",
+            };
+
+            ListItem::new(format!(
+                "{marker}{}{}{suffix}{warning}",
+                "  ".repeat(row.depth),
+                row.label
+            ))
+        });
+
+        let list = List::new(items)
+            .block(
+                Block::bordered().title(match (self.editing_filter, self.filter.is_empty()) {
+                    (true, _) => format!("Entries (filter: {}_)", self.filter),
+                    (false, false) => format!("Entries (filter: {})", self.filter),
+                    (false, true) => String::from("Entries"),
+                }),
+            )
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+
+        frame.render_stateful_widget(list, tree, &mut self.list_state);
+
+        let detail_lines = match &self.preview {
+            Some((index, _)) => detail_lines(&self.entries[*index], &self.details),
+            None => Vec::new(),
+        };
+
+        frame.render_widget(
+            Paragraph::new(detail_lines).block(Block::bordered().title("Details")),
+            details,
+        );
+
+        // Only the visible window is formatted so that large entries stay cheap to redraw
+        let take = preview.height as usize;
+
+        // Scrolling stops once the last line is at the bottom, rather than scrolling into nothing
+        let total = match &self.preview {
+            Some((_, Preview::Text { text, .. })) if !self.force_hex => text.lines().count(),
+            Some((_, Preview::Text { bytes, .. } | Preview::Hex(bytes))) => {
+                bytes.len().div_ceil(16)
+            }
+            _ => 0,
+        };
+        let last_page = total.saturating_sub(preview.height.saturating_sub(2) as usize);
+        self.preview_scroll = self
+            .preview_scroll
+            .min(last_page.try_into().unwrap_or(u16::MAX));
+        let skip = self.preview_scroll as usize;
+
+        let (title, lines) = match &self.preview {
+            Some((index, preview)) => {
+                let mut title = self.entries[*index].file_path.display().to_string();
+
+                if let Preview::Text {
+                    encoding, lossy, ..
+                } = preview
+                {
+                    let requested = match self.encoding {
+                        TextEncoding::Detect => "detected",
+                        TextEncoding::Forced(_) => "forced",
+                    };
+                    let lossy = if *lossy { ", not cleanly decoded" } else { "" };
+
+                    title.push_str(&format!(" [{} ({requested}){lossy}]", encoding.name()));
+                }
+
+                let lines = match preview {
+                    Preview::Text { text, .. } if !self.force_hex => text_lines(text, skip, take),
+                    Preview::Text { bytes, .. } => hex_lines(bytes, skip, take),
+                    Preview::Hex(bytes) => hex_lines(bytes, skip, take),
+                    Preview::Error(err) => vec![Line::raw(format!("Failed to read entry: {err}"))],
+                };
+
+                (title, lines)
+            }
+            None => (String::from("Preview"), Vec::new()),
+        };
+
+        let mut paragraph = Paragraph::new(lines).block(Block::bordered().title(title));
+
+        if self.wrap {
+            paragraph = paragraph.wrap(Wrap { trim: false });
+        }
+
+        frame.render_widget(paragraph, preview);
+
+        frame.render_widget(Paragraph::new(self.status.as_str()), status);
+    }
+}
+
+/// Height of the detail panel without its border
+const DETAIL_LINES: u16 = 5;
+
+fn detail_lines(entry: &NpaEntry, details: &Details) -> Vec<Line<'static>> {
+    let ratio = match entry.original_size {
+        0 => String::from("-"),
+        original => format!(
+            "{:.1}%",
+            entry.compressed_size as f64 / original as f64 * 100.0
+        ),
+    };
+
+    let mismatch = match details.size_mismatch {
+        Some(read) => format!("  (but read {read} bytes)"),
+        None => String::new(),
+    };
+
+    // infer doesn't know the video format Nitroplus games use
+    let mut kind = match details.mime {
+        Some(mime) => mime.to_string(),
+        None if entry.extension().as_deref() == Some("ngs") => String::from("Nitroplus video"),
+        None => String::from("unknown"),
+    };
+
+    if let Some((width, height)) = details.dimensions {
+        kind.push_str(&format!(", {width}×{height} px"));
+    }
+
+    vec![
+        Line::raw(format!("Path      {}", entry.file_path.display())),
+        Line::raw(format!(
+            "ID        {}  (type {})",
+            entry.file_id, entry.type_
+        )),
+        Line::raw(format!("Offset    {:#010X}", entry.offset)),
+        Line::raw(format!(
+            "Size      {} stored, {} original ({ratio}){mismatch}",
+            entry.compressed_size, entry.original_size
+        )),
+        Line::raw(format!("Content   {kind}")),
+    ]
+}
+
+fn decode_preview(bytes: Vec<u8>, encoding: TextEncoding) -> Preview {
+    let (text, encoding, lossy) = encoding.decode(&bytes);
+
+    Preview::Text {
+        bytes,
+        text,
+        encoding,
+        lossy,
+    }
+}
+
+/// Lines of `text` prefixed with their line number, padded to fit the last line of the file
+fn text_lines(text: &str, skip: usize, take: usize) -> Vec<Line<'_>> {
+    let width = text.lines().count().max(1).to_string().len();
+
+    text.lines()
+        .enumerate()
+        .skip(skip)
+        .take(take)
+        .map(|(number, line)| Line::raw(format!("{:>width$} │ {line}", number + 1)))
+        .collect()
+}
+
+fn hex_lines(bytes: &[u8], skip: usize, take: usize) -> Vec<Line<'static>> {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .skip(skip)
+        .take(take)
+        .map(|(row, chunk)| {
+            let hex: String = chunk.iter().map(|b| format!("{b:02X} ")).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+
+            Line::raw(format!("{:08X}  {hex:<48} {ascii}", row * 16))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_rows_opens_skipped_levels() {
+        let entries: Vec<NpaEntry> = ["a/x.png", "a/b/c/d.png", "e.png"]
+            .into_iter()
+            .map(|path| NpaEntry {
+                file_path: PathBuf::from(path),
+                ..Default::default()
+            })
+            .collect();
+
+        let rows: Vec<_> = build_rows(&entries, "", SortOrder::Path)
+            .into_iter()
+            .map(|row| (row.depth, row.label, row.entry))
+            .collect();
+
+        assert_eq!(
+            rows,
+            [
+                (0, String::from("a"), None),
+                (1, String::from("x.png"), Some(0)),
+                (1, String::from("b"), None),
+                (2, String::from("c"), None),
+                (3, String::from("d.png"), Some(1)),
+                (0, String::from("e.png"), Some(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_rows_sorts_within_directories() {
+        let entries: Vec<NpaEntry> = [("a/small.png", 1), ("a/large.png", 9), ("b.png", 5)]
+            .into_iter()
+            .map(|(path, original_size)| NpaEntry {
+                file_path: PathBuf::from(path),
+                original_size,
+                ..Default::default()
+            })
+            .collect();
+
+        let labels: Vec<_> = build_rows(&entries, "", SortOrder::OriginalSize)
+            .into_iter()
+            .map(|row| row.label)
+            .collect();
+
+        assert_eq!(labels, ["a", "large.png", "small.png", "b.png"]);
+    }
+
+    #[test]
+    fn test_build_rows_keeps_parents_of_matches() {
+        let entries: Vec<NpaEntry> = ["script/a01.nss", "voice/a01.ogg", "voice/b02.ogg"]
+            .into_iter()
+            .map(|path| NpaEntry {
+                file_path: PathBuf::from(path),
+                ..Default::default()
+            })
+            .collect();
+
+        let rows: Vec<_> = build_rows(&entries, "NSS", SortOrder::Path)
+            .into_iter()
+            .map(|row| (row.depth, row.label, row.entry))
+            .collect();
+
+        assert_eq!(
+            rows,
+            [
+                (0, String::from("script"), None),
+                (1, String::from("a01.nss"), Some(0)),
+            ]
+        );
+    }
+}
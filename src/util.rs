@@ -3,11 +3,16 @@ use std::io::{Read, Result};
 use chardetng::EncodingDetector;
 use encoding_rs::Encoding;
 
+use crate::TextEncoding;
+
 #[derive(Debug)]
 pub struct DecodedTextResult<'c> {
     text: String,
     encoding: &'c Encoding,
     had_errors: bool,
+
+    /// What detection would have picked, if a forced encoding failed and detection disagrees
+    detected: Option<&'c Encoding>,
 }
 
 impl<'c> DecodedTextResult<'c> {
@@ -15,6 +20,10 @@ impl<'c> DecodedTextResult<'c> {
         &self.text
     }
 
+    pub fn into_text(self) -> String {
+        self.text
+    }
+
     pub fn encoding(&self) -> &'c Encoding {
         self.encoding
     }
@@ -24,6 +33,20 @@ impl<'c> DecodedTextResult<'c> {
     }
 }
 
+impl DecodedTextResult<'static> {
+    /// Name of the encoding used, noting the detected one when a forced encoding didn't fit
+    pub fn encoding_description(&self) -> String {
+        match self.detected {
+            Some(detected) => format!(
+                "{} (requested, but it looks like {})",
+                self.encoding.name(),
+                detected.name()
+            ),
+            None => self.encoding.name().to_string(),
+        }
+    }
+}
+
 pub fn read_u32_le<R: Read>(r: &mut R) -> Result<u32> {
     let mut buf = [0u8; 4];
     r.read_exact(&mut buf)?;
@@ -36,17 +59,50 @@ pub fn read_u8<R: Read>(r: &mut R) -> Result<u8> {
     Ok(b[0])
 }
 
-pub fn decode_text(bytes: &[u8]) -> DecodedTextResult<'_> {
-    let mut encoding_detector = EncodingDetector::new();
-    encoding_detector.feed(bytes, true);
-
-    let encoding = encoding_detector.guess(None, true);
+pub fn decode_text(bytes: &[u8], encoding: TextEncoding) -> DecodedTextResult<'static> {
+    let forced = match encoding {
+        TextEncoding::Detect => None,
+        TextEncoding::Forced(encoding) => Some(encoding),
+    };
 
+    let encoding = forced.unwrap_or_else(|| detect_encoding(bytes));
     let (cow, _, had_errors) = encoding.decode(bytes);
 
+    let detected = forced
+        .filter(|_| had_errors)
+        .map(|_| detect_encoding(bytes))
+        .filter(|&detected| detected != encoding);
+
     DecodedTextResult {
         text: cow.to_string(),
         encoding,
         had_errors,
+        detected,
     }
 }
+
+fn detect_encoding(bytes: &[u8]) -> &'static Encoding {
+    let mut encoding_detector = EncodingDetector::new();
+    encoding_detector.feed(bytes, true);
+
+    encoding_detector.guess(None, true)
+}
+
+/// Whether `bytes` plausibly is a text file, either UTF-8 or Shift JIS without any control
+/// characters besides whitespace
+pub fn looks_like_text(bytes: &[u8]) -> bool {
+    let decodes = std::str::from_utf8(bytes).is_ok()
+        || encoding_rs::SHIFT_JIS
+            .decode_without_bom_handling_and_without_replacement(bytes)
+            .is_some();
+
+    decodes && !has_control_bytes(bytes)
+}
+
+/// Whether `bytes` contains control characters besides whitespace, which text in any of the
+/// encodings games use doesn't
+pub fn has_control_bytes(bytes: &[u8]) -> bool {
+    bytes
+        .iter()
+        .any(|&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r'))
+}
@@ -0,0 +1,242 @@
+//! Packing files into new NPA archives
+
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::{Compression, write::ZlibEncoder};
+
+use crate::{NPA_HEADER_SIZE, NpaError, NpaHead, decrypt_header};
+
+/// Builds an NPA archive from files added to it
+///
+/// Names are encrypted with the header keys, the same way every game does it unless it sets
+/// [`GameKeys::add_bytes_if_encrypted`](crate::GameKeys::add_bytes_if_encrypted). The data itself
+/// is written unencrypted, which every game reads as well, so packed archives can be read back
+/// with any [`Game`](crate::Game).
+#[derive(Debug, Clone)]
+pub struct NpaWriter {
+    key_1: u32,
+    key_2: u32,
+    compressed: bool,
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Default for NpaWriter {
+    fn default() -> Self {
+        NpaWriter {
+            key_1: 0x4147_414E,
+            key_2: 0x2100_0000,
+            compressed: false,
+            files: Vec::new(),
+        }
+    }
+}
+
+impl NpaWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the header keys the file names are encrypted with
+    pub fn with_keys(mut self, key_1: u32, key_2: u32) -> Self {
+        self.key_1 = key_1;
+        self.key_2 = key_2;
+        self
+    }
+
+    /// Sets whether the data of each file is zlib-compressed
+    pub fn compressed(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    /// Adds a file at `path`, relative to the root of the archive
+    ///
+    /// Directory entries for its parents are created automatically when writing.
+    pub fn add_file(&mut self, path: impl Into<PathBuf>, data: impl Into<Vec<u8>>) {
+        self.files.push((path.into(), data.into()));
+    }
+
+    /// Writes the archive to `out`
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<(), NpaError> {
+        let mut names: Vec<(String, Option<&[u8]>)> = Vec::new();
+        let mut directories = HashSet::new();
+
+        for (path, data) in &self.files {
+            let components = archive_components(path)?;
+
+            for depth in 1..components.len() {
+                let directory = components[..depth].join("\\");
+
+                if directories.insert(directory.clone()) {
+                    names.push((directory, None));
+                }
+            }
+
+            names.push((components.join("\\"), Some(data)));
+        }
+
+        let folder_count = names.iter().filter(|(_, data)| data.is_none()).count();
+        let mut header = NpaHead {
+            head: *b"NPA\x01\0\0\0",
+            key_1: self.key_1,
+            key_2: self.key_2,
+            encrypted: false,
+            compressed: self.compressed,
+            file_count: (names.len() - folder_count) as u32,
+            folder_count: folder_count as u32,
+            total_count: names.len() as u32,
+            start: 0,
+        };
+
+        let mut table = Vec::new();
+        let mut data_section = Vec::new();
+
+        for (index, (name, data)) in names.iter().enumerate() {
+            let (encoded, _, unmappable) = encoding_rs::SHIFT_JIS.encode(name);
+
+            if unmappable {
+                return Err(NpaError::UnencodableName(PathBuf::from(name)));
+            }
+
+            let encrypted_name: Vec<u8> = encoded
+                .iter()
+                .enumerate()
+                .map(|(x, byte)| {
+                    byte.wrapping_sub(decrypt_header(x as u32, index as u32, &header, false))
+                })
+                .collect();
+
+            let (type_, offset, stored_size, original_size) = match data {
+                Some(data) => {
+                    let stored = if self.compressed {
+                        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                        encoder.write_all(data)?;
+                        encoder.finish()?
+                    } else {
+                        data.to_vec()
+                    };
+
+                    let offset = data_section.len();
+                    data_section.extend_from_slice(&stored);
+
+                    (0u8, offset, stored.len(), data.len())
+                }
+                None => (1u8, 0, 0, 0),
+            };
+
+            table.extend_from_slice(&(encrypted_name.len() as u32).to_le_bytes());
+            table.extend_from_slice(&encrypted_name);
+            table.push(type_);
+            table.extend_from_slice(&(index as u32).to_le_bytes());
+            table.extend_from_slice(&to_u32(offset)?.to_le_bytes());
+            table.extend_from_slice(&to_u32(stored_size)?.to_le_bytes());
+            table.extend_from_slice(&to_u32(original_size)?.to_le_bytes());
+        }
+
+        header.start = to_u32(table.len())?;
+        to_u32(NPA_HEADER_SIZE as usize + table.len() + data_section.len())?;
+
+        out.write_all(&header.head)?;
+        out.write_all(&header.key_1.to_le_bytes())?;
+        out.write_all(&header.key_2.to_le_bytes())?;
+        out.write_all(&[header.compressed as u8, header.encrypted as u8])?;
+        out.write_all(&header.total_count.to_le_bytes())?;
+        out.write_all(&header.folder_count.to_le_bytes())?;
+        out.write_all(&header.file_count.to_le_bytes())?;
+        out.write_all(&[0u8; 8])?;
+        out.write_all(&header.start.to_le_bytes())?;
+        out.write_all(&table)?;
+        out.write_all(&data_section)?;
+
+        Ok(())
+    }
+}
+
+/// Splits `path` into the components of a name in the entry table
+fn archive_components(path: &Path) -> Result<Vec<String>, NpaError> {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => name
+                .to_str()
+                .map(str::to_string)
+                .ok_or_else(|| NpaError::UnencodableName(path.to_path_buf())),
+            _ => Err(NpaError::UnencodableName(path.to_path_buf())),
+        })
+        .collect()
+}
+
+/// Offsets and sizes are 32-bit, so archives are limited to 4 GiB
+fn to_u32(value: usize) -> Result<u32, NpaError> {
+    u32::try_from(value).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Archive would be larger than 4 GiB",
+        )
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{Game, NpaArchive, TextMode};
+
+    #[test]
+    fn test_round_trip() {
+        for compressed in [false, true] {
+            let mut writer = NpaWriter::new().compressed(compressed);
+            writer.add_file("system.dat", b"\x00\x01\x02".to_vec());
+            writer.add_file("script/op/main.nss", "いろは".as_bytes());
+            writer.add_file("script/ed.nss", b"end".to_vec());
+
+            let mut packed = Vec::new();
+            writer.write(&mut packed).unwrap();
+
+            let mut archive = NpaArchive::open(Cursor::new(packed), Game::ChaosHead)
+                .unwrap()
+                .with_text_mode(TextMode::Raw);
+
+            assert_eq!(archive.header().compressed, compressed);
+            assert_eq!(archive.header().file_count, 3);
+            assert_eq!(archive.header().folder_count, 2);
+            assert!(archive.info().warnings.is_empty());
+
+            let entries = archive.entries().to_vec();
+            let paths: Vec<_> = entries
+                .iter()
+                .map(|entry| entry.file_path.clone())
+                .collect();
+            assert_eq!(
+                paths,
+                [
+                    "system.dat",
+                    "script",
+                    "script/op",
+                    "script/op/main.nss",
+                    "script/ed.nss"
+                ]
+                .map(PathBuf::from)
+            );
+
+            for (path, data) in &writer.files {
+                let entry = entries.iter().find(|e| e.file_path == *path).unwrap();
+                assert_eq!(archive.read(entry, Game::ChaosHead).unwrap(), *data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_paths_outside_the_archive() {
+        let mut writer = NpaWriter::new();
+        writer.add_file("../escape.txt", b"".to_vec());
+
+        let err = writer.write(&mut Vec::new()).unwrap_err();
+        assert!(matches!(err, NpaError::UnencodableName(_)));
+    }
+}